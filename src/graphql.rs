@@ -0,0 +1,207 @@
+use async_graphql::{http::GraphiQLSource, Context, EmptySubscription, ErrorExtensions, InputObject, Object, Schema, SimpleObject};
+use axum::{extract::{ConnectInfo, State}, response::Html, Json};
+use chrono::Utc;
+use sqlx::Row;
+use std::{net::SocketAddr, sync::{Arc, OnceLock}};
+
+use super::{check_order_rate_limit, place_order, AppError, AppState, CreateOrder, IN_STOCK_SQL, MaybeAuth, OrderItemRequest, Product, PRODUCT_COLUMNS};
+
+// default and maximum page size for products and orders
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+type ApiSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+// resolvers only see the request's AppState and caller through the context, so one schema is
+// built on first use and shared by every request
+fn schema() -> &'static ApiSchema {
+    static SCHEMA: OnceLock<ApiSchema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish())
+}
+
+// GraphQL errors carry the same message and machine-readable code as the REST error bodies
+fn gql_error(e: AppError) -> async_graphql::Error {
+    let code = e.code();
+    if let AppError::DbError(db) = &e {
+        tracing::error!("db error: {}", db);
+    }
+    async_graphql::Error::new(e.message()).extend_with(|_, ext| ext.set("code", code))
+}
+
+struct Caller {
+    auth: MaybeAuth,
+    client_ip: String,
+}
+
+fn context<'a>(ctx: &'a Context<'_>) -> async_graphql::Result<(&'a AppState, &'a Caller)> {
+    Ok((ctx.data::<Arc<AppState>>()?.as_ref(), ctx.data::<Caller>()?))
+}
+
+#[derive(SimpleObject)]
+struct GqlProduct {
+    id: i64,
+    name: String,
+    description: Option<String>,
+    price_cents: i64,
+    stock: i32,
+    in_stock: bool,
+    category_id: Option<i64>,
+    created_at: String,
+    updated_at: String,
+}
+
+impl From<Product> for GqlProduct {
+    fn from(p: Product) -> Self {
+        GqlProduct {
+            id: p.id,
+            name: p.name,
+            description: p.description,
+            price_cents: p.price_cents.cents(),
+            stock: p.stock,
+            in_stock: p.in_stock,
+            category_id: p.category_id,
+            created_at: p.created_at,
+            updated_at: p.updated_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct GqlOrder {
+    id: String,
+    order_number: Option<String>,
+    status: String,
+    subtotal_cents: i64,
+    tax_cents: i64,
+    total_cents: i64,
+    created_at: String,
+    expires_at: Option<String>,
+}
+
+// what createOrder returns, matching the POST /orders response
+#[derive(SimpleObject)]
+struct GqlPlacedOrder {
+    id: String,
+    order_number: String,
+    subtotal_cents: i64,
+    adjustments_cents: i64,
+    tax_cents: i64,
+    total_cents: i64,
+    expires_at: Option<String>,
+}
+
+#[derive(InputObject)]
+struct OrderItemInput {
+    product_id: i64,
+    variant_id: Option<i64>,
+    quantity: i32,
+}
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // newest first, with the same visibility as GET /products
+    async fn products(&self, ctx: &Context<'_>, category_id: Option<i64>, limit: Option<i64>, include_out_of_stock: Option<bool>) -> async_graphql::Result<Vec<GqlProduct>> {
+        let (state, caller) = context(ctx)?;
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let stock_clause = if state.hides_out_of_stock(&caller.auth, include_out_of_stock) { format!(" AND {}", IN_STOCK_SQL) } else { String::new() };
+        let sql = format!(
+            "SELECT {} FROM products \
+             WHERE deleted_at IS NULL AND (?3 IS NULL OR category_id = ?3) AND (?1 OR ((available_from IS NULL OR available_from <= ?2) AND (available_until IS NULL OR available_until > ?2))){} \
+             ORDER BY id DESC LIMIT ?4",
+            PRODUCT_COLUMNS, stock_clause
+        );
+        let products = sqlx::query_as::<_, Product>(&sql)
+            .bind(caller.auth.is_admin())
+            .bind(Utc::now().to_rfc3339())
+            .bind(category_id)
+            .bind(limit)
+            .fetch_all(state.read_pool())
+            .await
+            .map_err(|e| gql_error(e.into()))?;
+
+        Ok(products.into_iter().map(GqlProduct::from).collect())
+    }
+
+    // same lookup (and request coalescing) as GET /products/:id
+    async fn product(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<GqlProduct>> {
+        let (state, caller) = context(ctx)?;
+        let product = state.product_reads.get(state.read_pool(), id, caller.auth.is_admin()).await.map_err(|e| gql_error(e.into()))?;
+        Ok(product.map(GqlProduct::from))
+    }
+
+    // the caller's own orders, newest first; admins see everyone's
+    async fn orders(&self, ctx: &Context<'_>, limit: Option<i64>) -> async_graphql::Result<Vec<GqlOrder>> {
+        let (state, caller) = context(ctx)?;
+        let customer_id = caller.auth.sub().ok_or_else(|| gql_error(AppError::Unauthorized))?;
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let rows = sqlx::query(
+            "SELECT id, order_number, status, subtotal_cents, tax_cents, total_cents, created_at FROM orders \
+             WHERE ?1 OR customer_id = ?2 ORDER BY created_at DESC LIMIT ?3"
+        )
+            .bind(caller.auth.is_admin())
+            .bind(customer_id)
+            .bind(limit)
+            .fetch_all(state.read_pool())
+            .await
+            .map_err(|e| gql_error(e.into()))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let status: String = r.get("status");
+                let created_at: String = r.get("created_at");
+                GqlOrder {
+                    id: r.get("id"),
+                    order_number: r.get::<Option<i64>, _>("order_number").map(super::format_order_number),
+                    expires_at: state.order_expires_at(&status, &created_at),
+                    status,
+                    subtotal_cents: r.get("subtotal_cents"),
+                    tax_cents: r.get("tax_cents"),
+                    total_cents: r.get("total_cents"),
+                    created_at,
+                }
+            })
+            .collect())
+    }
+}
+
+struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    // goes through the same rate limit and order placement as POST /orders
+    async fn create_order(&self, ctx: &Context<'_>, items: Vec<OrderItemInput>) -> async_graphql::Result<GqlPlacedOrder> {
+        let (state, caller) = context(ctx)?;
+        let payload = CreateOrder {
+            items: items.into_iter().map(|i| OrderItemRequest { product_id: i.product_id, variant_id: i.variant_id, quantity: i.quantity }).collect(),
+            adjustments: Vec::new(),
+            shipping_address_id: None,
+        };
+        check_order_rate_limit(state, caller.auth.sub(), &caller.client_ip).await.map_err(gql_error)?;
+        let order = place_order(state, &payload, caller.auth.sub(), Some(&caller.client_ip)).await.map_err(gql_error)?;
+        Ok(GqlPlacedOrder {
+            id: order.id,
+            order_number: order.order_number,
+            subtotal_cents: order.subtotal_cents.cents(),
+            adjustments_cents: order.adjustments_cents.cents(),
+            tax_cents: order.tax_cents.cents(),
+            total_cents: order.total_cents.cents(),
+            expires_at: order.expires_at,
+        })
+    }
+}
+
+pub async fn graphql_handler(auth: MaybeAuth, ConnectInfo(peer): ConnectInfo<SocketAddr>, State(state): State<Arc<AppState>>, Json(request): Json<async_graphql::Request>) -> Json<async_graphql::Response> {
+    let request = request.data(state).data(Caller { auth, client_ip: peer.ip().to_string() });
+    Json(schema().execute(request).await)
+}
+
+// GraphiQL, served only with GRAPHQL_PLAYGROUND=true
+pub async fn playground(State(state): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+    if !state.graphql_playground {
+        return Err(AppError::NotFound);
+    }
+    Ok(Html(GraphiQLSource::build().endpoint(&format!("{}/graphql", state.api_base_path)).finish()))
+}
@@ -1,318 +1,5381 @@
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    async_trait,
+    body::Body,
+    extract::{path::ErrorKind, rejection::PathRejection, ConnectInfo, MatchedPath, DefaultBodyLimit, FromRequest, FromRequestParts, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Router,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqlitePool, Row, Executor, Transaction};
-use std::{net::SocketAddr, sync::Arc};
-use tracing::{info, error};
-use tracing_subscriber::EnvFilter;
+use sqlx::{sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqliteRow, SqliteSynchronous}, FromRow, Row, Executor, Transaction};
+use std::{net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _};
 use dotenvy::dotenv;
 use uuid::Uuid;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use futures_util::{future::{BoxFuture, FutureExt, Shared}, TryFutureExt, TryStreamExt};
+use tokio_stream::wrappers::ReceiverStream;
+use tower::{util::option_layer, Layer};
+use tower_http::{cors::{AllowOrigin, CorsLayer}, decompression::RequestDecompressionLayer, normalize_path::NormalizePathLayer};
 use thiserror::Error;
-use chrono::Utc;
-use serde_json::json;
+use money::Money;
+
+#[cfg(feature = "graphql")]
+mod graphql;
+mod money;
+mod server;
+#[cfg(test)]
+mod tests;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod webhooks;
+use chrono::{DateTime, Utc};
 
-#[derive(Clone)]
 struct AppState {
     pool: SqlitePool,
+    // HS256 secret for bearer tokens; when unset every presented token is rejected
+    jwt_secret: Option<String>,
+    // optional read-only replica for GET handlers; writes always go to `pool`
+    replica: Option<SqlitePool>,
+    log_pool_choice: bool,
+    // bounds in-flight requests; see `concurrency_guard`
+    request_permits: Semaphore,
+    load_shed_timeout: Duration,
+    // per-request budget for database work (REQUEST_TIMEOUT_MS); unset means no deadline
+    request_timeout: Option<Duration>,
+    // fraction (0.0-1.0) of successful requests written to the access log; errors are always logged
+    access_log_sample_rate: f64,
+    // upper bounds (in characters) for product names and descriptions
+    max_name_len: usize,
+    max_description_len: usize,
+    // LIST_DESCRIPTION_MAX_CHARS: descriptions in list_products are cut to this many characters
+    list_description_max_chars: Option<usize>,
+    // HIDE_OUT_OF_STOCK_DEFAULT: storefront listings leave out products that are not in stock
+    // unless ?include_out_of_stock=true, see `hides_out_of_stock`
+    hide_out_of_stock_default: bool,
+    // upper bound for any stored stock level (MAX_STOCK)
+    max_stock: i32,
+    // ATTACHMENT_MAX_BYTES: size cap for one order attachment. A multipart upload is also bound
+    // by MAX_BODY_BYTES, so raising this past that limit needs both
+    attachment_max_bytes: usize,
+    // per-customer order rate limit (CUSTOMER_ORDER_LIMIT); None disables it
+    customer_order_limit: Option<i64>,
+    customer_order_window_secs: i64,
+    // PRODUCT_CACHE_MAX_AGE (seconds): lets shared caches keep anonymous product reads; unset
+    // sends no Cache-Control on them
+    product_cache_max_age: Option<u64>,
+    // global sales tax in basis points (TAX_RATE_BPS, default 0), applied to every order
+    tax_rate_bps: i64,
+    // MIN_ORDER_TOTAL_CENTS: orders whose product subtotal is below this are rejected. Tax never
+    // counts toward it; adjustments (shipping, gift wrap) only with MIN_ORDER_INCLUDES_ADJUSTMENTS
+    min_order_total_cents: Option<Money>,
+    min_order_includes_adjustments: bool,
+    // ORDER_EXPIRY_MINUTES: unpaid (pending) orders older than this are expired and restocked by
+    // `order_expiry_task`; unset or 0 disables expiry
+    order_expiry: Option<chrono::Duration>,
+    // GRAPHQL_PLAYGROUND: serve GraphiQL at /graphql/playground (graphql feature only)
+    #[cfg(feature = "graphql")]
+    graphql_playground: bool,
+    // reuse an X-Request-Id set by an upstream gateway instead of always minting a new one
+    trust_request_id: bool,
+    // mount point of the API routes, without a trailing slash (empty when mounted at the root)
+    api_base_path: String,
+    // outcome of the most recent stock-vs-ledger check, reported by /health/ready
+    last_stock_reconciliation: std::sync::Mutex<Option<StockReconciliationReport>>,
+    // whether WEBHOOK_URL is set, i.e. whether order events have anywhere to go
+    webhook_endpoint: bool,
+    // in-memory only: maintenance mode always starts disabled after a restart
+    maintenance: AtomicBool,
+    // counters behind /admin/diagnostics; reset on restart
+    diagnostics: Diagnostics,
+    product_reads: ProductReads,
+    // wakes `scheduled_price_task` when a new price is scheduled, which may be due sooner than
+    // the one it is sleeping towards
+    price_schedule: tokio::sync::Notify,
+}
+
+struct Diagnostics {
+    started: Instant,
+    requests: AtomicU64,
+    // keyed by AppError variant name, see `AppError::variant`
+    errors: std::sync::Mutex<std::collections::BTreeMap<&'static str, u64>>,
+}
+
+// statements sqlx reports as slow (longer than its 1s default threshold). Counted by
+// `SlowQueryCounter`, which is installed before AppState exists, hence a static
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+// tracing layer that only sees sqlx's WARN-level "slow statement" events and counts them;
+// it has its own filter so it neither depends on RUST_LOG nor enables sqlx's per-query logging
+struct SlowQueryCounter;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SlowQueryCounter {
+    fn on_event(&self, _event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl AppState {
+    // pending orders expire ORDER_EXPIRY_MINUTES after they were placed
+    fn order_expires_at(&self, status: &str, created_at: &str) -> Option<String> {
+        let expiry = self.order_expiry.filter(|_| status == "pending")?;
+        let created_at = DateTime::parse_from_rfc3339(created_at).ok()?;
+        Some((created_at.with_timezone(&Utc) + expiry).to_rfc3339())
+    }
+
+    // Location header for a newly created resource, e.g. ("products", 7) -> /api/v1/products/7
+    fn location(&self, collection: &str, id: impl std::fmt::Display) -> [(header::HeaderName, String); 1] {
+        [(header::LOCATION, format!("{}/{}/{}", self.api_base_path, collection, id))]
+    }
+
+    // events go through the outbox in the caller's transaction, so they are sent if and only if
+    // the change commits; without WEBHOOK_URL nothing would ever drain it, so no row is written
+    async fn enqueue_event(&self, tx: &mut Transaction<'_, sqlx::Sqlite>, event_type: &str, payload: serde_json::Value) -> Result<(), AppError> {
+        if self.webhook_endpoint {
+            webhooks::enqueue_outbox(tx, event_type, &payload).await?;
+        }
+        Ok(())
+    }
+
+    // whether a listing leaves out products that are not in stock, by the same definition as the
+    // `in_stock` field and facet (untracked products always count as in stock). Admins always
+    // see everything
+    fn hides_out_of_stock(&self, auth: &MaybeAuth, include_out_of_stock: Option<bool>) -> bool {
+        self.hide_out_of_stock_default && !auth.is_admin() && !include_out_of_stock.unwrap_or(false)
+    }
+
+    fn read_pool(&self) -> &SqlitePool {
+        match &self.replica {
+            Some(replica) => {
+                if self.log_pool_choice {
+                    debug!("using read replica pool");
+                }
+                replica
+            }
+            None => {
+                if self.log_pool_choice {
+                    debug!("using primary pool for read");
+                }
+                &self.pool
+            }
+        }
+    }
 }
 
+// default cap on request bodies, overridable via MAX_BODY_BYTES
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_ATTACHMENT_MAX_BYTES: usize = 1024 * 1024;
+
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 256;
+const DEFAULT_LOAD_SHED_TIMEOUT_MS: u64 = 500;
+
+// how long browsers may cache a CORS preflight answer, overridable via CORS_MAX_AGE_SECS
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 3600;
+
+// how long clients are told to wait before retrying a write rejected by maintenance mode
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 120;
+
+const DEFAULT_MAX_NAME_LEN: usize = 255;
+const DEFAULT_MAX_DESCRIPTION_LEN: usize = 10_000;
+const DEFAULT_MAX_STOCK: i32 = 1_000_000;
+
+const DEFAULT_CUSTOMER_ORDER_WINDOW_SECS: i64 = 3_600;
+
+// 0 disables the background stock reconciliation
+const DEFAULT_STOCK_RECONCILE_INTERVAL_SECS: u64 = 300;
+
+// how often pending orders are checked against ORDER_EXPIRY_MINUTES, and how many expire per pass
+const ORDER_EXPIRY_SWEEP_SECS: u64 = 60;
+// the scheduled price task sleeps until the next effective_at, but never longer than this
+const SCHEDULED_PRICE_MAX_SLEEP_SECS: u64 = 60;
+const ORDER_EXPIRY_BATCH: i64 = 100;
+
+const DEFAULT_API_BASE_PATH: &str = "/api/v1";
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
 macro_rules! json {
     ($($tt:tt)*) => { serde_json::json!($($tt)*) };
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// set once at startup from PRETTY_JSON; read by every `Json` response
+static PRETTY_JSON: AtomicBool = AtomicBool::new(false);
+
+// drop-in replacement for axum::Json: extraction is delegated to axum (after a JSON content-type
+// check), while responses are pretty-printed when PRETTY_JSON is on so every endpoint (and error)
+// formats the same way
+struct Json<T>(T);
+
+// accepts application/json and structured +json types, ignoring parameters such as charset
+fn is_json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    axum::Json<T>: FromRequest<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json_content_type(req.headers()) {
+            return Err(AppError::UnsupportedMediaType.into_response());
+        }
+        axum::Json::<T>::from_request(req, state)
+            .await
+            .map(|axum::Json(value)| Json(value))
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        if !PRETTY_JSON.load(Ordering::Relaxed) {
+            return axum::Json(self.0).into_response();
+        }
+        match serde_json::to_string_pretty(&self.0) {
+            Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(e) => {
+                error!("failed to serialize response: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+// drop-in replacement for axum's Path so malformed path segments (e.g. /products/abc) get the
+// usual JSON error body instead of axum's plain-text rejection
+struct Path<T>(T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    axum::extract::Path<T>: FromRequestParts<S, Rejection = PathRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Path::<T>::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Path(value)),
+            Err(PathRejection::FailedToDeserializePathParams(e)) => {
+                let index = match e.kind() {
+                    ErrorKind::ParseErrorAtIndex { index, .. } => *index,
+                    _ => 0,
+                };
+                let template = parts.extensions.get::<MatchedPath>().map(|p| p.as_str());
+                Err(AppError::BadRequest(path_error_message(template, index)))
+            }
+            Err(e) => Err(AppError::BadRequest(e.body_text())),
+        }
+    }
+}
+
+// names the parameter that failed to parse from the route template: `:id` takes the resource
+// before it ("/products/:id" -> "invalid product id"), others their own name ("invalid variant id")
+fn path_error_message(template: Option<&str>, index: usize) -> String {
+    let segments: Vec<&str> = template.unwrap_or("").split('/').filter(|s| !s.is_empty()).collect();
+    let param = segments.iter().enumerate().filter(|(_, s)| s.starts_with(':')).nth(index);
+    match param {
+        Some((i, &":id")) if i > 0 => {
+            let collection = segments[i - 1];
+            format!("invalid {} id", singular(collection))
+        }
+        Some((_, name)) => format!("invalid {}", name[1..].replace('_', " ")),
+        None => "invalid path parameter".into(),
+    }
+}
+
+// singular of a collection segment: categories -> category, addresses -> address, orders -> order
+fn singular(collection: &str) -> String {
+    if let Some(stem) = collection.strip_suffix("ies") {
+        return format!("{}y", stem);
+    }
+    if ["sses", "xes", "ches", "shes"].iter().any(|suffix| collection.ends_with(suffix)) {
+        return collection[..collection.len() - 2].to_string();
+    }
+    collection.strip_suffix('s').unwrap_or(collection).to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
 struct Product {
     id: i64,
     name: String,
     description: Option<String>,
-    price_cents: i64,
+    // free-form attributes (material, warranty, ...); always a JSON object when present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    price_cents: Money,
     stock: i32,
+    // false for unlimited (e.g. digital) products: orders neither check nor decrement their stock
+    track_stock: bool,
+    // stock > 0, or always true when stock isn't tracked
+    in_stock: bool,
+    category_id: Option<i64>,
+    // attribution is only serialized for admins; see `Product::without_attribution`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_by: Option<String>,
+    available_from: Option<String>,
+    available_until: Option<String>,
+    // availability relative to now ("scheduled", "available", "expired"); admins only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
     created_at: String,
+    updated_at: String,
+    // only present when the client asked for ?currency=XXX
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    converted: Option<ConvertedPrice>,
+    // only present for ?include=variants
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variants: Option<Vec<ProductVariant>>,
+    // only present for ?include=tiers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tiers: Option<Vec<PriceTier>>,
+    // only present for ?updated_since=..., where soft-deleted products are included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted: Option<bool>,
+    // set when list_products shortened the description to LIST_DESCRIPTION_MAX_CHARS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct PriceTier {
+    min_quantity: i32,
+    price_cents: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ProductVariant {
+    id: i64,
+    product_id: i64,
+    sku: String,
+    attributes: serde_json::Value,
+    price_cents: i64,
+    stock: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CreateVariant {
+    sku: String,
+    #[serde(default)]
+    attributes: Option<serde_json::Value>,
+    price_cents: i64,
+    stock: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ConvertedPrice {
+    currency: String,
+    converted_price_cents: i64,
+}
+
+// the products columns a Product is read from, see its FromRow impl
+const PRODUCT_COLUMNS: &str = "id, name, description, price_cents, stock, track_stock, category_id, created_by, updated_by, available_from, available_until, created_at, updated_at, metadata";
+
+// written out rather than derived: in_stock is computed from two columns, metadata is stored as
+// text (a value that doesn't parse is dropped instead of failing the read), and the remaining
+// fields depend on the request, so they start empty and handlers fill them in
+impl<'r> FromRow<'r, SqliteRow> for Product {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        let stock: i32 = row.try_get("stock")?;
+        let track_stock: bool = row.try_get("track_stock")?;
+        Ok(Product {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            description: row.try_get("description")?,
+            metadata: parse_metadata(row.try_get("metadata")?),
+            price_cents: row.try_get("price_cents")?,
+            stock,
+            track_stock,
+            in_stock: Product::is_in_stock(track_stock, stock),
+            category_id: row.try_get("category_id")?,
+            created_by: row.try_get("created_by")?,
+            updated_by: row.try_get("updated_by")?,
+            available_from: row.try_get("available_from")?,
+            available_until: row.try_get("available_until")?,
+            status: None,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+            converted: None,
+            variants: None,
+            tiers: None,
+            deleted: None,
+            truncated: None,
+        })
+    }
+}
+
+impl Product {
+    fn is_in_stock(track_stock: bool, stock: i32) -> bool {
+        !track_stock || stock > 0
+    }
+
+    // list responses only carry a preview of long descriptions; get_product returns the full text
+    fn truncate_description(mut self, max_chars: Option<usize>) -> Self {
+        if let (Some(max_chars), Some(description)) = (max_chars, self.description.as_mut())
+            && let Some((cut, _)) = description.char_indices().nth(max_chars)
+        {
+            description.truncate(cut);
+            self.truncated = Some(true);
+        }
+        self
+    }
+
+    fn without_attribution(mut self) -> Self {
+        self.created_by = None;
+        self.updated_by = None;
+        self
+    }
+
+    fn for_viewer(self, auth: &MaybeAuth) -> Self {
+        if auth.is_admin() { self.with_status() } else { self.without_attribution() }
+    }
+
+    fn with_status(mut self) -> Self {
+        let now = Utc::now().to_rfc3339();
+        let status = if self.available_from.as_ref().is_some_and(|from| *from > now) {
+            "scheduled"
+        } else if self.available_until.as_ref().is_some_and(|until| *until <= now) {
+            "expired"
+        } else {
+            "available"
+        };
+        self.status = Some(status.to_string());
+        self
+    }
 }
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
 struct CreateProduct {
     name: String,
     description: Option<String>,
+    metadata: Option<serde_json::Value>,
     price_cents: i64,
     stock: i32,
+    // defaults to true; see `Product::track_stock`
+    track_stock: Option<bool>,
+    category_id: Option<i64>,
+    available_from: Option<String>,
+    available_until: Option<String>,
+    // admin-only override used when migrating products from another system
+    created_at: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
 struct UpdateProduct {
     name: Option<String>,
     description: Option<String>,
+    metadata: Option<serde_json::Value>,
     price_cents: Option<i64>,
     stock: Option<i32>,
+    track_stock: Option<bool>,
+    category_id: Option<i64>,
+    // omitted keeps the stored bound, null clears it
+    #[serde(default, deserialize_with = "nullable")]
+    available_from: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    available_until: Option<Option<String>>,
+}
+
+// tells an explicit null (Some(None)) apart from an omitted field (None, via #[serde(default)])
+fn nullable<'de, T: Deserialize<'de>, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
 struct OrderItemRequest {
     product_id: i64,
+    // when set, price and stock come from this variant of the product instead of the product itself
+    variant_id: Option<i64>,
     quantity: i32,
 }
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
 struct CreateOrder {
     items: Vec<OrderItemRequest>,
+    // order-level charges that are not products, e.g. gift wrap or shipping
+    #[serde(default)]
+    adjustments: Vec<OrderAdjustment>,
+    // one of the customer's shipping addresses; the order keeps a copy, see `OrderAddress`
+    #[serde(default)]
+    shipping_address_id: Option<i64>,
 }
 
-#[derive(Debug, Serialize)]
-struct OrderResponse {
-    id: String,
-    total_cents: i64,
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderAdjustment {
+    label: String,
+    amount_cents: i64,
 }
 
-#[derive(Error, Debug)]
-enum AppError {
-    #[error("Not found")] NotFound,
-    #[error("Bad request: {0}")] BadRequest(String),
-    #[error("Database error")] DbError(#[from] sqlx::Error),
-    #[error("Internal error")] InternalError,
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderStatusBatchRequest {
+    ids: Vec<String>,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, body) = match &self {
-            AppError::NotFound => (StatusCode::NOT_FOUND, Json(json!({"error": "Not Found"}))),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, Json(json!({"error": msg}))),
-            AppError::DbError(e) => {
-                error!("db error: {}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Database error"})))
-            }
-            AppError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Internal error"}))),
-        };
-        (status, body).into_response()
-    }
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderStatusEntry {
+    id: String,
+    // None when no order with this id exists
+    status: Option<String>,
 }
 
-async fn list_products(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Product>>, AppError> {
-    let rows = sqlx::query("SELECT id, name, description, price_cents, stock, created_at FROM products ORDER BY id DESC")
-        .fetch_all(&state.pool)
-        .await?;
-
-    let products: Vec<Product> = rows
-        .into_iter()
-        .map(|r| Product {
-            id: r.get::<i64, _>("id"),
-            name: r.get::<String, _>("name"),
-            description: r.get::<Option<String>, _>("description"),
-            price_cents: r.get::<i64, _>("price_cents"),
-            stock: r.get::<i32, _>("stock"),
-            created_at: r.get::<String, _>("created_at"),
-        })
-        .collect();
-
-    Ok(Json(products))
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct UpdateOrderStatus {
+    status: String,
 }
 
-async fn get_product(Path(id): Path<i64>, State(state): State<Arc<AppState>>) -> Result<Json<Product>, AppError> {
-    let row = sqlx::query("SELECT id, name, description, price_cents, stock, created_at FROM products WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&state.pool)
-        .await?;
-
-    match row {
-        Some(r) => Ok(Json(Product {
-            id: r.get::<i64, _>("id"),
-            name: r.get::<String, _>("name"),
-            description: r.get::<Option<String>, _>("description"),
-            price_cents: r.get::<i64, _>("price_cents"),
-            stock: r.get::<i32, _>("stock"),
-            created_at: r.get::<String, _>("created_at"),
-        })),
-        None => Err(AppError::NotFound),
-    }
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CreateRefund {
+    amount_cents: i64,
+    reason: Option<String>,
 }
 
-async fn create_product(State(state): State<Arc<AppState>>, Json(payload): Json<CreateProduct>) -> Result<(StatusCode, Json<Product>), AppError> {
-    if payload.name.trim().is_empty() {
-        return Err(AppError::BadRequest("name must not be empty".into()));
-    }
-    if payload.price_cents <= 0 {
-        return Err(AppError::BadRequest("price_cents must be > 0".into()));
-    }
-    let now = Utc::now().to_rfc3339();
-    let mut tx = state.pool.begin().await?;
-    let res = sqlx::query("INSERT INTO products (name, description, price_cents, stock, created_at) VALUES (?, ?, ?, ?, ?)")
-        .bind(&payload.name)
-        .bind(&payload.description)
-        .bind(payload.price_cents)
-        .bind(payload.stock)
-        .bind(&now)
-        .execute(tx.as_mut())  
-        .await?;
-
-    let inserted_id = res.last_insert_rowid();
-
-    tx.commit().await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct RefundResponse {
+    refund_id: i64,
+    order_id: String,
+    amount_cents: i64,
+    // running totals for the order after this refund
+    refunded_cents: i64,
+    refundable_cents: i64,
+}
 
-    let row = sqlx::query("SELECT id, name, description, price_cents, stock, created_at FROM products WHERE id = ?")
-        .bind(inserted_id)
-        .fetch_one(&state.pool)
-        .await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderEvent {
+    event_type: String,
+    detail: Option<String>,
+    created_at: String,
+}
 
-    let product = Product {
-        id: row.get("id"),
-        name: row.get("name"),
-        description: row.get("description"),
-        price_cents: row.get("price_cents"),
-        stock: row.get("stock"),
-        created_at: row.get("created_at"),
-    };
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderResponse {
+    id: String,
+    order_number: String,
+    subtotal_cents: Money,
+    adjustments_cents: Money,
+    tax_cents: Money,
+    total_cents: Money,
+    // when the order expires unless paid; only set while order expiry is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+}
 
-    Ok((StatusCode::CREATED, Json(product)))
+#[derive(Debug, Deserialize)]
+struct SalesQuery {
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
 }
 
-async fn update_product(Path(id): Path<i64>, State(state): State<Arc<AppState>>, Json(payload): Json<UpdateProduct>) -> Result<Json<Product>, AppError> {
-    // perform an updatable SQL using COALESCE so that omitted fields keep their existing values
-    let mut tx = state.pool.begin().await?;
-    let _ = sqlx::query(
-        "UPDATE products SET name = COALESCE(?, name), description = COALESCE(?, description), price_cents = COALESCE(?, price_cents), stock = COALESCE(?, stock) WHERE id = ?"
-    )
-    .bind(payload.name.as_deref())
-    .bind(payload.description.as_deref())
-    .bind(payload.price_cents)
-    .bind(payload.stock)
-    .bind(id)
-    .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
-    .await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ProductSale {
+    order_id: String,
+    quantity: i32,
+    unit_price_cents: i64,
+    ordered_at: String,
+}
 
-    tx.commit().await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct SalesSummary {
+    line_items: i64,
+    units_sold: i64,
+    revenue_cents: i64,
+}
 
-    let row = sqlx::query("SELECT id, name, description, price_cents, stock, created_at FROM products WHERE id = ?")
-        .bind(id)
-        .fetch_optional(&state.pool)
-        .await?;
+// canonical envelope for every collection endpoint: `items` is always present (possibly empty),
+// `total` counts the whole result set rather than just this page, and `next_cursor` is the value
+// to send back for the next page, or null when there is none
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct Collection<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+    total: i64,
+}
 
-    match row {
-        Some(r) => Ok(Json(Product {
-            id: r.get("id"),
-            name: r.get("name"),
-            description: r.get("description"),
-            price_cents: r.get("price_cents"),
-            stock: r.get("stock"),
-            created_at: r.get("created_at"),
-        })),
-        None => Err(AppError::NotFound),
+impl<T> Collection<T> {
+    // an unpaginated result: everything is in `items`
+    fn complete(items: Vec<T>) -> Self {
+        let total = items.len() as i64;
+        Collection { items, next_cursor: None, total }
     }
 }
 
-async fn delete_product(Path(id): Path<i64>, State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
-    let _ = sqlx::query("DELETE FROM products WHERE id = ?")
-        .bind(id)
-        .execute(&state.pool)
-        .await?;
-
-    Ok(StatusCode::NO_CONTENT)
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ProductSalesResponse {
+    product_id: i64,
+    #[serde(flatten)]
+    page: Collection<ProductSale>,
+    summary: SalesSummary,
+    limit: i64,
+    offset: i64,
 }
 
-async fn create_order(State(state): State<Arc<AppState>>, Json(payload): Json<CreateOrder>) -> Result<(StatusCode, Json<OrderResponse>), AppError> {
-    if payload.items.is_empty() {
-        return Err(AppError::BadRequest("order must contain at least one item".into()));
-    }
-    let mut tx: Transaction<'_, sqlx::Sqlite> = state.pool.begin().await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct Bestseller {
+    product_id: i64,
+    name: String,
+    units_sold: i64,
+    // admin-only; the ranking itself is public
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revenue_cents: Option<i64>,
+}
 
-    let mut total_cents: i64 = 0;
+#[derive(Debug, Deserialize)]
+struct RelatedQuery {
+    limit: Option<i64>,
+}
 
-    for item in &payload.items {
-        let row = sqlx::query("SELECT stock, price_cents FROM products WHERE id = ?")
-            .bind(item.product_id)
-            .fetch_optional(tx.as_mut())  // Use tx.as_mut() for transaction executor
-            .await?;
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct BoughtTogether {
+    #[serde(flatten)]
+    product: Product,
+    times_bought_together: i64,
+}
 
-        let row = match row {
-            Some(r) => r,
-            None => return Err(AppError::BadRequest(format!("product {} not found", item.product_id))),
-        };
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct MaintenanceState {
+    enabled: bool,
+}
 
-        let stock: i32 = row.get("stock");
-        let unit_price: i64 = row.get("price_cents");
+#[derive(Error, Debug)]
+enum AppError {
+    #[error("Not found")] NotFound,
+    #[error("Unauthorized")] Unauthorized,
+    #[error("Forbidden")] Forbidden,
+    #[error("Bad request: {0}")] BadRequest(String),
+    #[error("Database error")] DbError(#[from] sqlx::Error),
+    #[error("Precondition failed")] PreconditionFailed,
+    #[error("Unsupported media type")] UnsupportedMediaType,
+    #[error("Conflict: {0}")] Conflict(&'static str),
+    #[error("Too many requests")] TooManyRequests,
+    #[error("Payload too large (max {0} bytes)")] PayloadTooLarge(usize),
+    #[error("Query deadline exceeded")] QueryDeadlineExceeded,
+    #[error("Internal error")] InternalError,
+    #[error("Route not found: {0}")] RouteNotFound(String),
+    #[error("Method {0} not allowed on {1}")] MethodNotAllowed(Method, String),
+    #[error("Maintenance")] Maintenance,
+    #[error("Overloaded")] Overloaded,
+}
 
-        if stock < item.quantity {
-            return Err(AppError::BadRequest(format!("not enough stock for product {}", item.product_id)));
+impl AppError {
+    // stable machine-readable code sent as `code` in every error body, whatever the locale
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "not_found",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::DbError(_) => "database_error",
+            AppError::PreconditionFailed => "precondition_failed",
+            AppError::TooManyRequests => "too_many_orders",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::QueryDeadlineExceeded => "query_deadline_exceeded",
+            AppError::Conflict(code) => code,
+            AppError::UnsupportedMediaType => "unsupported_media_type",
+            AppError::InternalError => "internal_error",
+            AppError::RouteNotFound(_) => "route_not_found",
+            AppError::MethodNotAllowed(..) => "method_not_allowed",
+            AppError::Maintenance => "maintenance",
+            AppError::Overloaded => "overloaded",
         }
-
-        total_cents += (item.quantity as i64) * unit_price;
     }
 
-    let order_id = Uuid::new_v4().to_string();
-    let now = Utc::now().to_rfc3339();
-    sqlx::query("INSERT INTO orders (id, total_cents, created_at) VALUES (?, ?, ?)")
-        .bind(&order_id)
-        .bind(total_cents)
-        .bind(&now)
-        .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
-        .await?;
-
-    for item in &payload.items {
-        let row = sqlx::query("SELECT price_cents FROM products WHERE id = ?")
-            .bind(item.product_id)
-            .fetch_one(tx.as_mut())  // Use tx.as_mut() for transaction executor
-            .await?;
-        let unit_price: i64 = row.get("price_cents");
-
-        sqlx::query("INSERT INTO order_items (order_id, product_id, quantity, unit_price_cents) VALUES (?, ?, ?, ?)")
-            .bind(&order_id)
-            .bind(item.product_id)
-            .bind(item.quantity)
-            .bind(unit_price)
-            .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
-            .await?;
+    // the client-facing text in the request's locale. Outside a request (no scope) the catalog
+    // falls back to English, as does a missing entry
+    fn message(&self) -> String {
+        let code = self.code();
+        let locale = LOCALE.try_with(|l| *l).unwrap_or(Locale::En);
+        match self {
+            AppError::BadRequest(msg) => msg.clone(),
+            _ => error_message(code, locale).or_else(|| error_message(code, Locale::En)).unwrap_or(code).to_string(),
+        }
+    }
 
-        sqlx::query("UPDATE products SET stock = stock - ? WHERE id = ?")
-            .bind(item.quantity)
-            .bind(item.product_id)
-            .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
-            .await?;
+    fn variant(&self) -> &'static str {
+        match self {
+            AppError::NotFound => "NotFound",
+            AppError::Unauthorized => "Unauthorized",
+            AppError::Forbidden => "Forbidden",
+            AppError::BadRequest(_) => "BadRequest",
+            AppError::DbError(_) => "DbError",
+            AppError::PreconditionFailed => "PreconditionFailed",
+            AppError::TooManyRequests => "TooManyRequests",
+            AppError::PayloadTooLarge(_) => "PayloadTooLarge",
+            AppError::QueryDeadlineExceeded => "QueryDeadlineExceeded",
+            AppError::Conflict(_) => "Conflict",
+            AppError::UnsupportedMediaType => "UnsupportedMediaType",
+            AppError::InternalError => "InternalError",
+            AppError::RouteNotFound(_) => "RouteNotFound",
+            AppError::MethodNotAllowed(..) => "MethodNotAllowed",
+            AppError::Maintenance => "Maintenance",
+            AppError::Overloaded => "Overloaded",
+        }
     }
+}
 
-    tx.commit().await?;
+// attached to error responses so the access log can count errors by variant
+#[derive(Clone, Copy)]
+struct ErrorVariant(&'static str);
 
-    Ok((StatusCode::CREATED, Json(OrderResponse { id: order_id, total_cents })))
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Locale {
+    En,
+    Es,
 }
 
-async fn get_order(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>, AppError> {
-    let row = sqlx::query("SELECT id, total_cents, created_at FROM orders WHERE id = ?")
-        .bind(&id)
-        .fetch_optional(&state.pool)
-        .await?;
+tokio::task_local! {
+    // negotiated from Accept-Language by `negotiate_locale` for the duration of a request
+    static LOCALE: Locale;
+}
 
-    if let Some(r) = row {
-        let items = sqlx::query("SELECT product_id, quantity, unit_price_cents FROM order_items WHERE order_id = ?")
-            .bind(&id)
-            .fetch_all(&state.pool)
-            .await?;
+// best supported language by q-value, English when nothing matches. Only the primary subtag
+// counts, so es-MX and es-ES both get Spanish
+fn parse_accept_language(header: &str) -> Locale {
+    header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let q = parts.find_map(|p| p.trim().strip_prefix("q=")).map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            let locale = match tag.split('-').next()?.to_ascii_lowercase().as_str() {
+                "en" => Locale::En,
+                "es" => Locale::Es,
+                _ => return None,
+            };
+            (q > 0.0).then_some((locale, q))
+        })
+        .fold(None, |best: Option<(Locale, f32)>, (locale, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((locale, q)),
+        })
+        .map_or(Locale::En, |(locale, _)| locale)
+}
 
-        let items_json: Vec<serde_json::Value> = items.into_iter().map(|it| {
-            serde_json::json!({
-                "product_id": it.get::<i64, _>("product_id"),
-                "quantity": it.get::<i32, _>("quantity"),
-                "unit_price_cents": it.get::<i64, _>("unit_price_cents"),
-            })
-        }).collect();
+async fn negotiate_locale(req: Request, next: Next) -> Response {
+    let locale = req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()).map_or(Locale::En, parse_accept_language);
+    LOCALE.scope(locale, next.run(req)).await
+}
 
-        let resp = serde_json::json!({
-            "id": r.get::<String, _>("id"),
-            "total_cents": r.get::<i64, _>("total_cents"),
-            "created_at": r.get::<String, _>("created_at"),
-            "items": items_json,
-        });
+tokio::task_local! {
+    // when the current request must be finished by, set by `request_deadline` if REQUEST_TIMEOUT_MS is configured
+    static DEADLINE: Instant;
+}
 
-        Ok(Json(resp))
-    } else {
-        Err(AppError::NotFound)
+// starts the request's time budget. It sits outside the concurrency guard, so time spent waiting
+// for a slot counts against it too
+async fn request_deadline(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    match state.request_timeout {
+        Some(timeout) => DEADLINE.scope(Instant::now() + timeout, next.run(req)).await,
+        None => next.run(req).await,
     }
 }
 
-async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {.
-    let mut conn = pool.acquire().await?;
+// awaits a query with whatever is left of the request's budget. Past the deadline the query is
+// dropped and the request fails with 504 rather than holding its connection for a client that
+// has likely given up. Without a configured timeout this is a plain await
+async fn before_deadline<T>(query: impl std::future::Future<Output = Result<T, sqlx::Error>>) -> Result<T, AppError> {
+    let Ok(deadline) = DEADLINE.try_with(|d| *d) else {
+        return Ok(query.await?);
+    };
+    match tokio::time::timeout(deadline.saturating_duration_since(Instant::now()), query).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            warn!("query abandoned at the request deadline");
+            Err(AppError::QueryDeadlineExceeded)
+        }
+    }
+}
+
+// catalog of the fixed error messages. BadRequest messages are built at each call site (ids,
+// limits) and stay English. English keeps the exact strings sent before localization, which for
+// conflicts is the code itself
+fn error_message(code: &str, locale: Locale) -> Option<&'static str> {
+    Some(match (code, locale) {
+        ("not_found", Locale::En) => "Not Found",
+        ("not_found", Locale::Es) => "No encontrado",
+        ("unauthorized", Locale::En) => "Unauthorized",
+        ("unauthorized", Locale::Es) => "No autorizado",
+        ("forbidden", Locale::En) => "Forbidden",
+        ("forbidden", Locale::Es) => "Prohibido",
+        ("database_error", Locale::En) => "Database error",
+        ("database_error", Locale::Es) => "Error de base de datos",
+        ("precondition_failed", Locale::En) => "Precondition Failed",
+        ("precondition_failed", Locale::Es) => "Precondición fallida",
+        ("too_many_orders", Locale::En) => "too many orders",
+        ("too_many_orders", Locale::Es) => "demasiados pedidos",
+        ("payload_too_large", Locale::En) => "payload_too_large",
+        ("payload_too_large", Locale::Es) => "la solicitud es demasiado grande",
+        ("query_deadline_exceeded", Locale::En) => "query_deadline_exceeded",
+        ("query_deadline_exceeded", Locale::Es) => "la consulta superó el tiempo límite",
+        ("duplicate_name", Locale::En) => "duplicate_name",
+        ("duplicate_name", Locale::Es) => "el nombre ya existe",
+        ("adjustment_id_reused", Locale::En) => "adjustment_id_reused",
+        ("adjustment_id_reused", Locale::Es) => "client_adjustment_id ya se usó para otro ajuste",
+        ("unsupported_media_type", Locale::En) => "expected application/json",
+        ("unsupported_media_type", Locale::Es) => "se esperaba application/json",
+        ("internal_error", Locale::En) => "Internal error",
+        ("internal_error", Locale::Es) => "Error interno",
+        ("route_not_found", Locale::En) => "route not found",
+        ("route_not_found", Locale::Es) => "ruta no encontrada",
+        ("method_not_allowed", Locale::En) => "method not allowed",
+        ("method_not_allowed", Locale::Es) => "método no permitido",
+        ("maintenance", Locale::En) => "maintenance",
+        ("maintenance", Locale::Es) => "servicio en mantenimiento",
+        ("overloaded", Locale::En) => "overloaded",
+        ("overloaded", Locale::Es) => "servicio sobrecargado",
+        _ => return None,
+    })
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match &self {
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::DbError(e) => {
+                error!("db error: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            AppError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::QueryDeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RouteNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Maintenance | AppError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let code = self.code();
+        let mut body = json!({"error": self.message(), "code": code});
+        match &self {
+            AppError::PayloadTooLarge(max_bytes) => body["max_bytes"] = json!(max_bytes),
+            AppError::RouteNotFound(path) => body["path"] = json!(path),
+            AppError::MethodNotAllowed(method, path) => {
+                body["method"] = json!(method.as_str());
+                body["path"] = json!(path);
+            }
+            _ => {}
+        }
+        let mut res = (status, Json(body)).into_response();
+        // how long clients should back off before retrying
+        let retry_after = match &self {
+            AppError::Maintenance => Some(MAINTENANCE_RETRY_AFTER_SECS),
+            AppError::Overloaded => Some(1),
+            _ => None,
+        };
+        if let Some(secs) = retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res.extensions_mut().insert(ErrorVariant(self.variant()));
+        res
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    role: Option<String>,
+}
+
+// optional bearer-token authentication: no Authorization header means an anonymous caller,
+// while a header carrying an invalid or expired token is rejected with 401
+struct MaybeAuth(Option<Claims>);
+
+impl MaybeAuth {
+    fn sub(&self) -> Option<&str> {
+        self.0.as_ref().map(|c| c.sub.as_str())
+    }
+
+    fn is_admin(&self) -> bool {
+        self.0.as_ref().is_some_and(|c| c.role.as_deref() == Some("admin"))
+    }
+
+    // 401 for anonymous callers, 403 for authenticated non-admins
+    fn require_admin(&self) -> Result<(), AppError> {
+        match &self.0 {
+            None => Err(AppError::Unauthorized),
+            Some(_) if !self.is_admin() => Err(AppError::Forbidden),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // like `require_admin`, but the customer themselves is let through too
+    fn require_customer(&self, customer_id: &str) -> Result<(), AppError> {
+        match self.sub() {
+            None => Err(AppError::Unauthorized),
+            Some(sub) if sub != customer_id && !self.is_admin() => Err(AppError::Forbidden),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for MaybeAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(header::AUTHORIZATION) else {
+            return Ok(MaybeAuth(None));
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(AppError::Unauthorized)?;
+        let secret = state.jwt_secret.as_deref().ok_or(AppError::Unauthorized)?;
+        let data = jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(secret.as_bytes()), &Validation::new(Algorithm::HS256))
+            .map_err(|_| AppError::Unauthorized)?;
+        Ok(MaybeAuth(Some(data.claims)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProductReadQuery {
+    currency: Option<String>,
+    // comma-separated extra sections for get_product: "variants", "tiers"
+    include: Option<String>,
+    // incremental sync for list_products: only products changed after this RFC3339 time
+    updated_since: Option<String>,
+    // inclusive RFC3339 bounds on created_at for list_products
+    created_from: Option<String>,
+    created_to: Option<String>,
+    // list_products ordering: a SORT_FIELDS name, "-" prefixed for descending (default "-id")
+    sort: Option<String>,
+    // page size for list_products; without it every matching product is returned
+    limit: Option<i64>,
+    // next_cursor from the previous page
+    cursor: Option<String>,
+    // overrides HIDE_OUT_OF_STOCK_DEFAULT for list_products
+    include_out_of_stock: Option<bool>,
+}
+
+impl ProductReadQuery {
+    fn includes(&self, section: &str) -> bool {
+        self.include.as_deref().is_some_and(|inc| inc.split(',').any(|s| s.trim() == section))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct Category {
+    id: i64,
+    name: String,
+    created_at: String,
+    updated_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CategoryName {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CurrencyRate {
+    code: String,
+    // value of one unit of this currency expressed in the base (catalog) currency
+    rate_to_base: f64,
+    // decimal places of the currency's minor unit, e.g. 2 for EUR, 0 for JPY
+    minor_units: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct UpsertCurrencyRate {
+    rate_to_base: f64,
+    minor_units: Option<i32>,
+}
+
+impl CurrencyRate {
+    // prices are stored in base-currency cents; convert to the target currency's minor units
+    fn convert(&self, price_cents: i64) -> ConvertedPrice {
+        let major = price_cents as f64 / 100.0 / self.rate_to_base;
+        let minor = major * 10f64.powi(self.minor_units);
+        ConvertedPrice { currency: self.code.clone(), converted_price_cents: minor.round() as i64 }
+    }
+}
+
+fn normalize_currency_code(code: &str) -> Result<String, AppError> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AppError::BadRequest("currency must be a 3-letter ISO code".into()));
+    }
+    Ok(code)
+}
+
+async fn load_currency_rate(pool: &SqlitePool, code: Option<&str>) -> Result<Option<CurrencyRate>, AppError> {
+    let Some(code) = code else {
+        return Ok(None);
+    };
+    let code = normalize_currency_code(code)?;
+    let row = sqlx::query("SELECT code, rate_to_base, minor_units FROM currency_rates WHERE code = ?")
+        .bind(&code)
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(r) => Ok(Some(CurrencyRate {
+            code: r.get("code"),
+            rate_to_base: r.get("rate_to_base"),
+            minor_units: r.get("minor_units"),
+        })),
+        None => Err(AppError::BadRequest(format!("unknown currency {}", code))),
+    }
+}
+
+const MAX_METADATA_FILTERS: usize = 10;
+
+// `?meta.<key>=<value>` pairs, matched against the top-level metadata key as text. Keys are
+// restricted to [A-Za-z0-9_] so they can be spliced into a JSON path safely
+fn metadata_filters(raw: &std::collections::HashMap<String, String>) -> Result<Vec<(String, String)>, AppError> {
+    let mut filters: Vec<(String, String)> = raw
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix("meta.").map(|key| (key.to_string(), v.clone())))
+        .collect();
+    if filters.len() > MAX_METADATA_FILTERS {
+        return Err(AppError::BadRequest(format!("at most {} metadata filters", MAX_METADATA_FILTERS)));
+    }
+    if let Some((key, _)) = filters.iter().find(|(k, _)| k.is_empty() || !k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+        return Err(AppError::BadRequest(format!("invalid metadata key {}", key)));
+    }
+    filters.sort();
+    Ok(filters)
+}
+
+// SQL form of `Product::is_in_stock`
+const IN_STOCK_SQL: &str = "(NOT track_stock OR stock > 0)";
+
+// the availability window check shared by every product read: admins (parameter ?{admin}) see
+// scheduled and expired products too, everyone else only those available at ?{now}. `table` is
+// the column qualifier, e.g. "p." when products is joined
+fn available_sql(table: &str, admin: usize, now: usize) -> String {
+    format!(
+        "(?{admin} OR (({t}available_from IS NULL OR {t}available_from <= ?{now}) AND ({t}available_until IS NULL OR {t}available_until > ?{now})))",
+        t = table, admin = admin, now = now
+    )
+}
+
+// list_products sort keys. Like FACET_FIELDS, only these names ever reach the SQL
+const SORT_FIELDS: &[&str] = &["id", "name", "price_cents", "created_at"];
+const MAX_PRODUCT_PAGE: i64 = 200;
+
+#[derive(Debug, Clone, Copy)]
+struct ProductSort {
+    field: &'static str,
+    descending: bool,
+}
+
+impl ProductSort {
+    fn parse(raw: Option<&str>) -> Result<Self, AppError> {
+        let Some(raw) = raw else {
+            return Ok(ProductSort { field: "id", descending: true });
+        };
+        let (name, descending) = match raw.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (raw, false),
+        };
+        let Some(field) = SORT_FIELDS.iter().find(|f| **f == name) else {
+            return Err(AppError::BadRequest(format!("cannot sort by {} (allowed: {})", name, SORT_FIELDS.join(", "))));
+        };
+        Ok(ProductSort { field, descending })
+    }
+
+    fn name(&self) -> String {
+        format!("{}{}", if self.descending { "-" } else { "" }, self.field)
+    }
+
+    fn value(&self, product: &Product) -> serde_json::Value {
+        match self.field {
+            "name" => json!(product.name),
+            "price_cents" => json!(product.price_cents),
+            "created_at" => json!(product.created_at),
+            _ => json!(product.id),
+        }
+    }
+}
+
+// keyset position after the last product of a page: its sort value plus id as the tiebreaker,
+// so pages stay stable when products before the position are inserted or deleted. Sent to
+// clients as base64url JSON and meant to be opaque
+#[derive(Debug, Serialize, Deserialize)]
+struct ProductCursor {
+    sort: String,
+    value: serde_json::Value,
+    id: i64,
+}
+
+impl ProductCursor {
+    fn after(sort: ProductSort, product: &Product) -> Self {
+        ProductCursor { sort: sort.name(), value: sort.value(product), id: product.id }
+    }
+
+    fn encode(&self) -> String {
+        use base64::Engine;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    // a cursor only makes sense for the sort it was issued under
+    fn decode(raw: &str, sort: ProductSort) -> Result<Self, AppError> {
+        use base64::Engine;
+        let cursor: ProductCursor = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(raw)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| AppError::BadRequest("malformed cursor".into()))?;
+        if cursor.sort != sort.name() {
+            return Err(AppError::BadRequest(format!("cursor was issued for sort {}, not {}", cursor.sort, sort.name())));
+        }
+        let textual = matches!(sort.field, "name" | "created_at");
+        if (textual && !cursor.value.is_string()) || (!textual && !cursor.value.is_i64()) {
+            return Err(AppError::BadRequest("malformed cursor".into()));
+        }
+        Ok(cursor)
+    }
+}
+
+type SqliteQuery<'q> = sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>;
+
+// binds the parameters shared by the list and count queries, in list_products' numbering
+fn bind_product_filters<'q>(mut query: SqliteQuery<'q>, admin: bool, created_from: &'q Option<String>, created_to: &'q Option<String>, updated_since: &'q Option<String>, filters: &'q [(String, String)]) -> SqliteQuery<'q> {
+    query = query.bind(admin).bind(Utc::now().to_rfc3339()).bind(created_from).bind(created_to);
+    if let Some(since) = updated_since {
+        query = query.bind(since);
+    }
+    for (key, value) in filters {
+        query = query.bind(format!("$.{}", key)).bind(value);
+    }
+    query
+}
+
+async fn list_products(auth: MaybeAuth, headers: HeaderMap, Query(params): Query<ProductReadQuery>, Query(raw_params): Query<std::collections::HashMap<String, String>>, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let rate = load_currency_rate(state.read_pool(), params.currency.as_deref()).await?;
+    let updated_since = parse_timestamp_param("updated_since", params.updated_since.as_deref())?;
+    let sync = updated_since.is_some();
+    let created_from = parse_timestamp_param("created_from", params.created_from.as_deref())?;
+    let created_to = parse_timestamp_param("created_to", params.created_to.as_deref())?;
+    if let (Some(from), Some(to)) = (&created_from, &created_to) && from > to {
+        return Err(AppError::BadRequest("created_from must not be after created_to".into()));
+    }
+    let filters = metadata_filters(&raw_params)?;
+    let sort = ProductSort::parse(params.sort.as_deref())?;
+    let cursor = params.cursor.as_deref().map(|raw| ProductCursor::decode(raw, sort)).transpose()?;
+    let limit = params.limit.map(|l| l.clamp(1, MAX_PRODUCT_PAGE));
+    if sync && (params.sort.is_some() || cursor.is_some() || limit.is_some()) {
+        return Err(AppError::BadRequest("sort, limit and cursor cannot be combined with updated_since".into()));
+    }
+    let paginated = limit.is_some() || cursor.is_some();
+
+    // numbered parameters: ?1 admin, ?2 now, ?3 created_from, ?4 created_to, ?5 updated_since
+    // (sync only), then two per filter, then the cursor's value and id, then the limit
+    let first_filter_param = if sync { 6 } else { 5 };
+    let meta_clause: String = (0..filters.len())
+        .map(|i| format!(" AND CAST(json_extract(metadata, ?{}) AS TEXT) = ?{}", first_filter_param + 2 * i, first_filter_param + 2 * i + 1))
+        .collect();
+    // sync mode mirrors every change, so a product going out of stock is never hidden from it
+    let stock_clause = if state.hides_out_of_stock(&auth, params.include_out_of_stock) { format!(" AND {}", IN_STOCK_SQL) } else { String::new() };
+    let list_filter_clause = format!(
+        "deleted_at IS NULL AND (?3 IS NULL OR created_at >= ?3) AND (?4 IS NULL OR created_at <= ?4) AND {}{}",
+        available_sql("", 1, 2), stock_clause
+    );
+    let sql = if sync {
+        format!(
+            "SELECT {}, deleted_at FROM products \
+             WHERE updated_at > ?5 AND (?3 IS NULL OR created_at >= ?3) AND (?4 IS NULL OR created_at <= ?4) AND {}{} ORDER BY updated_at ASC, id ASC",
+            PRODUCT_COLUMNS, available_sql("", 1, 2), meta_clause
+        )
+    } else {
+        let (dir, op) = if sort.descending { ("DESC", "<") } else { ("ASC", ">") };
+        let mut next_param = first_filter_param + 2 * filters.len();
+        let mut keyset_clause = String::new();
+        if cursor.is_some() {
+            keyset_clause = format!(" AND ({f} {op} ?{v} OR ({f} = ?{v} AND id {op} ?{id}))", f = sort.field, op = op, v = next_param, id = next_param + 1);
+            next_param += 2;
+        }
+        let limit_clause = limit.map(|_| format!(" LIMIT ?{}", next_param)).unwrap_or_default();
+        format!(
+            "SELECT {}, deleted_at FROM products \
+             WHERE {}{}{} ORDER BY {f} {dir}, id {dir}{}",
+            PRODUCT_COLUMNS, list_filter_clause, meta_clause, keyset_clause, limit_clause, f = sort.field, dir = dir
+        )
+    };
+
+    // in sync mode soft-deleted products are returned too (flagged `deleted`) so a client can
+    // drop them, and rows come oldest change first so the last updated_at is the next cursor
+    let mut query = bind_product_filters(sqlx::query(&sql), auth.is_admin(), &created_from, &created_to, &updated_since, &filters);
+    if let Some(cursor) = &cursor {
+        query = match &cursor.value {
+            serde_json::Value::String(v) => query.bind(v.clone()),
+            v => query.bind(v.as_i64()),
+        };
+        query = query.bind(cursor.id);
+    }
+    // one extra row tells whether there is a next page
+    if let Some(limit) = limit {
+        query = query.bind(limit + 1);
+    }
+    let mut rows = before_deadline(query.fetch_all(state.read_pool())).await?;
+    let has_more = limit.is_some_and(|limit| rows.len() as i64 > limit);
+    if let Some(limit) = limit {
+        rows.truncate(limit as usize);
+    }
+
+    let products = rows
+        .iter()
+        .map(|r| {
+            let mut product = Product::from_row(r)?;
+            product.converted = rate.as_ref().map(|rate| rate.convert(product.price_cents.cents()));
+            product.deleted = sync.then(|| r.get::<Option<String>, _>("deleted_at").is_some());
+            Ok(product.for_viewer(&auth).truncate_description(state.list_description_max_chars))
+        })
+        .collect::<Result<Vec<Product>, sqlx::Error>>()?;
+
+    // a sync client resumes from the newest change it has seen; a paged listing from the last
+    // product of this page
+    let next_cursor = if sync {
+        products.last().map(|p| p.updated_at.clone())
+    } else if has_more {
+        products.last().map(|p| ProductCursor::after(sort, p).encode())
+    } else {
+        None
+    };
+    // when paging, total still counts every matching product, not just the rest from the cursor
+    let total = if paginated {
+        let count_sql = format!("SELECT COUNT(*) AS total FROM products WHERE {}{}", list_filter_clause, meta_clause);
+        let count = bind_product_filters(sqlx::query(&count_sql), auth.is_admin(), &created_from, &created_to, &updated_since, &filters);
+        before_deadline(count.fetch_one(state.read_pool())).await?.get("total")
+    } else {
+        products.len() as i64
+    };
+    let last_modified = products.iter().map(|p| p.updated_at.clone()).max();
+    let collection = Collection { total, items: products, next_cursor };
+    Ok(with_cache_control(&state, &auth, conditional_json_response(&headers, &collection, last_modified.as_deref())))
+}
+
+#[derive(Debug, Deserialize)]
+struct FacetQuery {
+    field: String,
+    include_out_of_stock: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct FacetValue {
+    value: serde_json::Value,
+    count: i64,
+}
+
+// facetable fields and the SQL expression each one groups by. Only these strings ever reach the
+// query, so the field name from the URL is never interpolated
+const FACET_FIELDS: &[(&str, &str)] = &[
+    ("category_id", "category_id"),
+    ("in_stock", IN_STOCK_SQL),
+];
+
+// distinct values of one field with how many visible products have each, over the same
+// products list_products would return
+async fn product_facets(auth: MaybeAuth, Query(params): Query<FacetQuery>, State(state): State<Arc<AppState>>) -> Result<Json<Collection<FacetValue>>, AppError> {
+    let Some((field, expr)) = FACET_FIELDS.iter().find(|(name, _)| *name == params.field) else {
+        let allowed: Vec<&str> = FACET_FIELDS.iter().map(|(name, _)| *name).collect();
+        return Err(AppError::BadRequest(format!("field {} is not facetable (allowed: {})", params.field, allowed.join(", "))));
+    };
+
+    // with out-of-stock products hidden, the in_stock facet only ever has the true bucket
+    let stock_clause = if state.hides_out_of_stock(&auth, params.include_out_of_stock) { format!(" AND {}", IN_STOCK_SQL) } else { String::new() };
+    let sql = format!(
+        "SELECT {} AS value, COUNT(*) AS count FROM products \
+         WHERE deleted_at IS NULL AND {}{} \
+         GROUP BY value ORDER BY count DESC, value",
+        expr, available_sql("", 1, 2), stock_clause
+    );
+    let query = sqlx::query(&sql)
+        .bind(auth.is_admin())
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(state.read_pool());
+    let rows = before_deadline(query).await?;
+
+    let values = rows
+        .into_iter()
+        .map(|r| {
+            let value = match *field {
+                "in_stock" => serde_json::Value::from(r.get::<bool, _>("value")),
+                _ => serde_json::Value::from(r.get::<Option<i64>, _>("value")),
+            };
+            FacetValue { value, count: r.get("count") }
+        })
+        .collect();
+    Ok(Json(Collection::complete(values)))
+}
+
+type SharedProductRead = Shared<BoxFuture<'static, Result<Option<Product>, Arc<sqlx::Error>>>>;
+
+// single-flight for get_product: concurrent reads of the same product (and visibility, since
+// admins also see unscheduled products) share one query instead of each hitting the database
+#[derive(Default)]
+struct ProductReads {
+    in_flight: std::sync::Mutex<std::collections::HashMap<(i64, bool), SharedProductRead>>,
+    // reads that joined a query already in flight, and reads that had to start one
+    coalesced: AtomicU64,
+    queried: AtomicU64,
+}
+
+// removes the leader's entry once its query is done, or if its request is dropped first.
+// Waiters keep their own handle to the shared query, so they still get its result
+struct InFlightRead<'a> {
+    reads: &'a ProductReads,
+    key: (i64, bool),
+}
+
+impl Drop for InFlightRead<'_> {
+    fn drop(&mut self) {
+        self.reads.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ProductReads {
+    async fn get(&self, pool: &SqlitePool, id: i64, admin: bool) -> Result<Option<Product>, sqlx::Error> {
+        let key = (id, admin);
+        let (read, _leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(read) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    (read.clone(), None)
+                }
+                None => {
+                    self.queried.fetch_add(1, Ordering::Relaxed);
+                    let read = fetch_product(pool.clone(), id, admin).map_err(Arc::new).boxed().shared();
+                    in_flight.insert(key, read.clone());
+                    (read, Some(InFlightRead { reads: self, key }))
+                }
+            }
+        };
+        // sqlx::Error isn't Clone; the last holder gets the original, others a copy of its message
+        read.await.map_err(|e| Arc::try_unwrap(e).unwrap_or_else(|e| sqlx::Error::Protocol(e.to_string())))
+    }
+}
+
+// the product as stored, before anything that depends on the request (currency, includes, viewer)
+async fn fetch_product(pool: SqlitePool, id: i64, admin: bool) -> Result<Option<Product>, sqlx::Error> {
+    let sql = format!(
+        "SELECT {} FROM products \
+         WHERE id = ?1 AND deleted_at IS NULL AND {}",
+        PRODUCT_COLUMNS, available_sql("", 2, 3)
+    );
+    sqlx::query_as::<_, Product>(&sql)
+        .bind(id)
+        .bind(admin)
+        .bind(Utc::now().to_rfc3339())
+        .fetch_optional(&pool)
+        .await
+}
+
+// axum also routes HEAD here and strips the body, so HEAD /products/:id gets the same status,
+// ETag and Last-Modified as a GET
+async fn get_product(Path(id): Path<i64>, auth: MaybeAuth, headers: HeaderMap, Query(params): Query<ProductReadQuery>, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let rate = load_currency_rate(state.read_pool(), params.currency.as_deref()).await?;
+    let product = before_deadline(state.product_reads.get(state.read_pool(), id, auth.is_admin())).await?;
+
+    match product {
+        Some(mut product) => {
+            if params.includes("variants") {
+                product.variants = Some(load_variants(state.read_pool(), id).await?);
+            }
+            if params.includes("tiers") {
+                product.tiers = Some(load_price_tiers(state.read_pool(), id).await?);
+            }
+            product.converted = rate.as_ref().map(|rate| rate.convert(product.price_cents.cents()));
+            let product = product.for_viewer(&auth);
+            Ok(with_cache_control(&state, &auth, conditional_json_response(&headers, &product, Some(&product.updated_at))))
+        }
+        None => Err(AppError::NotFound),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ProductBatchGetRequest {
+    ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ProductBatch {
+    items: Vec<Product>,
+    // requested ids with no product the caller may see (unknown, deleted or not yet available)
+    missing: Vec<i64>,
+}
+
+const MAX_PRODUCT_BATCH: usize = 200;
+
+// many get_product calls in one query, e.g. to hydrate a cart. Products come back in the order
+// they were asked for, each id once, with the same visibility as GET /products/:id
+async fn batch_get_products(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<ProductBatchGetRequest>) -> Result<Json<ProductBatch>, AppError> {
+    if payload.ids.len() > MAX_PRODUCT_BATCH {
+        return Err(AppError::BadRequest(format!("at most {} ids per request", MAX_PRODUCT_BATCH)));
+    }
+    let mut ids = payload.ids;
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    if ids.is_empty() {
+        return Ok(Json(ProductBatch { items: Vec::new(), missing: Vec::new() }));
+    }
+
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let sql = format!(
+        "SELECT {} FROM products \
+         WHERE id IN ({}) AND deleted_at IS NULL AND (? OR ((available_from IS NULL OR available_from <= ?) AND (available_until IS NULL OR available_until > ?)))",
+        PRODUCT_COLUMNS, placeholders
+    );
+    let now = Utc::now().to_rfc3339();
+    let mut query = sqlx::query_as::<_, Product>(&sql);
+    for id in &ids {
+        query = query.bind(id);
+    }
+    let products = before_deadline(query.bind(auth.is_admin()).bind(&now).bind(&now).fetch_all(state.read_pool())).await?;
+
+    let mut found: std::collections::HashMap<i64, Product> = products.into_iter().map(|p| (p.id, p)).collect();
+    let mut batch = ProductBatch { items: Vec::with_capacity(found.len()), missing: Vec::new() };
+    for id in ids {
+        match found.remove(&id) {
+            Some(product) => batch.items.push(product.for_viewer(&auth)),
+            None => batch.missing.push(id),
+        }
+    }
+    Ok(Json(batch))
+}
+
+// anything that depends on who is asking must never be stored by a shared cache; anonymous reads
+// are public and may be cached for PRODUCT_CACHE_MAX_AGE, then revalidated with the ETag
+fn with_cache_control(state: &AppState, auth: &MaybeAuth, mut res: Response) -> Response {
+    let value = if auth.0.is_some() {
+        Some("private, no-store".to_string())
+    } else {
+        state.product_cache_max_age.map(|max_age| format!("public, max-age={}", max_age))
+    };
+    if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+        res.headers_mut().insert(header::VARY, HeaderValue::from_static("Authorization"));
+    }
+    res
+}
+
+// the ETag hashes the serialized representation, so it changes with anything that changes the
+// body (viewer role, ?currency, ?include) and not just with updated_at
+fn conditional_json_response<T: Serialize>(headers: &HeaderMap, value: &T, last_modified: Option<&str>) -> Response {
+    use std::hash::{Hash, Hasher};
+
+    let Ok(body) = serde_json::to_vec(value) else {
+        return AppError::InternalError.into_response();
+    };
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    let mut validators = vec![(header::ETAG, etag.clone())];
+    if let Some(updated) = last_modified.and_then(|ts| DateTime::parse_from_rfc3339(ts).ok()) {
+        validators.push((header::LAST_MODIFIED, updated.with_timezone(&Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string()));
+    }
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        }));
+    let mut res = if not_modified { StatusCode::NOT_MODIFIED.into_response() } else { Json(value).into_response() };
+    for (name, value) in validators {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    res
+}
+
+async fn load_variants(pool: &SqlitePool, product_id: i64) -> Result<Vec<ProductVariant>, AppError> {
+    let rows = sqlx::query("SELECT id, product_id, sku, attributes_json, price_cents, stock FROM product_variants WHERE product_id = ? ORDER BY id")
+        .bind(product_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| ProductVariant {
+            id: r.get("id"),
+            product_id: r.get("product_id"),
+            sku: r.get("sku"),
+            attributes: serde_json::from_str(r.get("attributes_json")).unwrap_or_else(|_| json!({})),
+            price_cents: r.get("price_cents"),
+            stock: r.get("stock"),
+        })
+        .collect())
+}
+
+async fn list_variants(Path(id): Path<i64>, State(state): State<Arc<AppState>>) -> Result<Json<Collection<ProductVariant>>, AppError> {
+    let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(state.read_pool())
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound);
+    }
+    Ok(Json(Collection::complete(load_variants(state.read_pool(), id).await?)))
+}
+
+async fn create_variant(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CreateVariant>) -> Result<(StatusCode, Json<ProductVariant>), AppError> {
+    auth.require_admin()?;
+    if payload.sku.trim().is_empty() {
+        return Err(AppError::BadRequest("sku must not be empty".into()));
+    }
+    if payload.price_cents <= 0 {
+        return Err(AppError::BadRequest("price_cents must be > 0".into()));
+    }
+    validate_stock(&state, payload.stock)?;
+    let attributes = payload.attributes.unwrap_or_else(|| json!({}));
+    if !attributes.is_object() {
+        return Err(AppError::BadRequest("attributes must be a JSON object".into()));
+    }
+
+    let mut tx = begin_write(&state.pool).await?;
+    let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(tx.as_mut())
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound);
+    }
+    let taken = sqlx::query("SELECT id FROM product_variants WHERE sku = ?")
+        .bind(&payload.sku)
+        .fetch_optional(tx.as_mut())
+        .await?;
+    if taken.is_some() {
+        return Err(AppError::BadRequest(format!("sku {} already exists", payload.sku)));
+    }
+
+    let row = sqlx::query("INSERT INTO product_variants (product_id, sku, attributes_json, price_cents, stock) VALUES (?, ?, ?, ?, ?) RETURNING id")
+        .bind(id)
+        .bind(&payload.sku)
+        .bind(attributes.to_string())
+        .bind(payload.price_cents)
+        .bind(payload.stock)
+        .fetch_one(tx.as_mut())
+        .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(ProductVariant {
+        id: row.get("id"),
+        product_id: id,
+        sku: payload.sku,
+        attributes,
+        price_cents: payload.price_cents,
+        stock: payload.stock,
+    })))
+}
+
+async fn delete_variant(Path((id, variant_id)): Path<(i64, i64)>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
+    auth.require_admin()?;
+    let res = sqlx::query("DELETE FROM product_variants WHERE id = ? AND product_id = ?")
+        .bind(variant_id)
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn load_price_tiers(pool: &SqlitePool, product_id: i64) -> Result<Vec<PriceTier>, AppError> {
+    let rows = sqlx::query("SELECT min_quantity, price_cents FROM price_tiers WHERE product_id = ? ORDER BY min_quantity")
+        .bind(product_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| PriceTier { min_quantity: r.get("min_quantity"), price_cents: r.get("price_cents") })
+        .collect())
+}
+
+// replaces a product's whole tier table; tiers must be listed by strictly ascending min_quantity
+async fn set_price_tiers(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(tiers): Json<Vec<PriceTier>>) -> Result<Json<Vec<PriceTier>>, AppError> {
+    auth.require_admin()?;
+    for (i, tier) in tiers.iter().enumerate() {
+        if tier.min_quantity < 1 {
+            return Err(AppError::BadRequest("min_quantity must be >= 1".into()));
+        }
+        if tier.price_cents <= 0 {
+            return Err(AppError::BadRequest("price_cents must be > 0".into()));
+        }
+        if i > 0 && tier.min_quantity <= tiers[i - 1].min_quantity {
+            return Err(AppError::BadRequest("tiers must have strictly ascending min_quantity".into()));
+        }
+    }
+
+    let mut tx = begin_write(&state.pool).await?;
+    let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(tx.as_mut())
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    sqlx::query("DELETE FROM price_tiers WHERE product_id = ?")
+        .bind(id)
+        .execute(tx.as_mut())
+        .await?;
+    for tier in &tiers {
+        sqlx::query("INSERT INTO price_tiers (product_id, min_quantity, price_cents) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(tier.min_quantity)
+            .bind(tier.price_cents)
+            .execute(tx.as_mut())
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(Json(tiers))
+}
+
+async fn create_product(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CreateProduct>) -> Result<(StatusCode, [(header::HeaderName, String); 1], Json<Product>), AppError> {
+    if payload.name.trim().is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".into()));
+    }
+    if payload.price_cents <= 0 {
+        return Err(AppError::BadRequest("price_cents must be > 0".into()));
+    }
+    validate_product_text(&state, Some(&payload.name), payload.description.as_deref())?;
+    validate_stock(&state, payload.stock)?;
+    let metadata = validate_metadata(payload.metadata.as_ref())?;
+    let available_from = parse_timestamp_param("available_from", payload.available_from.as_deref())?;
+    let available_until = parse_timestamp_param("available_until", payload.available_until.as_deref())?;
+    validate_availability_window(available_from.as_deref(), available_until.as_deref())?;
+    let now = Utc::now().to_rfc3339();
+    // only admins may backdate a product, otherwise anyone could spoof its age
+    let created_at = match parse_timestamp_param("created_at", payload.created_at.as_deref())? {
+        Some(ts) => {
+            auth.require_admin()?;
+            ts
+        }
+        None => now.clone(),
+    };
+    let created_by = auth.sub();
+    // RETURNING hands back the stored row from the INSERT itself, so no follow-up SELECT is needed
+    let sql = format!("INSERT INTO products (name, description, price_cents, stock, track_stock, category_id, created_by, available_from, available_until, created_at, updated_at, metadata) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}", PRODUCT_COLUMNS);
+    let sql = sql.as_str();
+    let product = with_tx(&state.pool, |tx| Box::pin(async move {
+        let product = sqlx::query_as::<_, Product>(sql)
+            .bind(&payload.name)
+            .bind(&payload.description)
+            .bind(payload.price_cents)
+            .bind(payload.stock)
+            .bind(payload.track_stock.unwrap_or(true))
+            .bind(payload.category_id)
+            .bind(created_by)
+            .bind(&available_from)
+            .bind(&available_until)
+            .bind(&created_at)
+            .bind(&now)
+            .bind(&metadata)
+            .fetch_one(tx.as_mut())
+            .await
+            .map_err(duplicate_name_error)?;
+        record_stock_change(tx, product.id, i64::from(payload.stock), "initial", None).await?;
+        record_product_event(tx, "product.created", product.id).await?;
+        Ok(product)
+    })).await?;
+
+    Ok((StatusCode::CREATED, state.location("products", product.id), Json(product.for_viewer(&auth))))
+}
+
+async fn update_product(Path(id): Path<i64>, auth: MaybeAuth, headers: HeaderMap, State(state): State<Arc<AppState>>, Json(payload): Json<UpdateProduct>) -> Result<Json<Product>, AppError> {
+    validate_product_text(&state, payload.name.as_deref(), payload.description.as_deref())?;
+    if let Some(stock) = payload.stock {
+        validate_stock(&state, stock)?;
+    }
+    let metadata = validate_metadata(payload.metadata.as_ref())?;
+    let available_from = payload.available_from.as_ref().map(|v| parse_timestamp_param("available_from", v.as_deref())).transpose()?;
+    let available_until = payload.available_until.as_ref().map(|v| parse_timestamp_param("available_until", v.as_deref())).transpose()?;
+    // an anonymous update keeps the last known editor rather than clearing it
+    let updated_by = auth.sub();
+
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        // the window has to be validated against the stored bound when only one side is updated
+        let existing = sqlx::query("SELECT stock, available_from, available_until, updated_at FROM products WHERE id = ? AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .ok_or(AppError::NotFound)?;
+        check_unmodified_since(&headers, existing.get("updated_at"))?;
+        validate_availability_window(
+            available_from.as_ref().map_or(existing.get::<Option<&str>, _>("available_from"), Option::as_deref),
+            available_until.as_ref().map_or(existing.get::<Option<&str>, _>("available_until"), Option::as_deref),
+        )?;
+
+        // perform an updatable SQL using COALESCE so that omitted fields keep their existing values
+        let _ = sqlx::query(
+            "UPDATE products SET name = COALESCE(?, name), description = COALESCE(?, description), price_cents = COALESCE(?, price_cents), stock = COALESCE(?, stock), track_stock = COALESCE(?, track_stock), category_id = COALESCE(?, category_id), \
+             available_from = CASE WHEN ? THEN ? ELSE available_from END, available_until = CASE WHEN ? THEN ? ELSE available_until END, metadata = COALESCE(?, metadata), updated_by = COALESCE(?, updated_by), updated_at = ? WHERE id = ?"
+        )
+        .bind(payload.name.as_deref())
+        .bind(payload.description.as_deref())
+        .bind(payload.price_cents)
+        .bind(payload.stock)
+        .bind(payload.track_stock)
+        .bind(payload.category_id)
+        .bind(available_from.is_some())
+        .bind(available_from.flatten())
+        .bind(available_until.is_some())
+        .bind(available_until.flatten())
+        .bind(&metadata)
+        .bind(updated_by)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
+        .await
+        .map_err(duplicate_name_error)?;
+        if let Some(stock) = payload.stock {
+            let delta = i64::from(stock) - i64::from(existing.get::<i32, _>("stock"));
+            record_stock_change(tx, id, delta, "adjustment", None).await?;
+        }
+        record_product_event(tx, "product.updated", id).await?;
+        Ok(())
+    })).await?;
+
+    let product = sqlx::query_as::<_, Product>(&format!("SELECT {} FROM products WHERE id = ?", PRODUCT_COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(product.for_viewer(&auth)))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct PriceAdjustRequest {
+    category_id: Option<i64>,
+    percent: f64,
+}
+
+async fn adjust_prices(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<PriceAdjustRequest>) -> Result<Json<serde_json::Value>, AppError> {
+    auth.require_admin()?;
+    if !payload.percent.is_finite() || payload.percent <= -100.0 {
+        return Err(AppError::BadRequest("percent must be greater than -100".into()));
+    }
+    let factor = 1.0 + payload.percent / 100.0;
+    let now = Utc::now().to_rfc3339();
+
+    let mut tx = begin_write(&state.pool).await?;
+    let rows = sqlx::query("SELECT id, price_cents FROM products WHERE deleted_at IS NULL AND (?1 IS NULL OR category_id = ?1)")
+        .bind(payload.category_id)
+        .fetch_all(tx.as_mut())
+        .await?;
+
+    let mut updated = 0;
+    for row in rows {
+        let id: i64 = row.get("id");
+        let old_price: i64 = row.get("price_cents");
+        // rounding can still take a 1-cent price to zero, so clamp to the smallest valid price
+        let new_price = ((old_price as f64 * factor).round() as i64).max(1);
+        if new_price == old_price {
+            continue;
+        }
+
+        sqlx::query("UPDATE products SET price_cents = ?, updated_at = ? WHERE id = ?")
+            .bind(new_price)
+            .bind(&now)
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?;
+        sqlx::query("INSERT INTO price_history (product_id, old_price_cents, new_price_cents, reason, changed_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(id)
+            .bind(old_price)
+            .bind(new_price)
+            .bind(format!("price-adjust {}%", payload.percent))
+            .bind(&now)
+            .execute(tx.as_mut())
+            .await?;
+        record_product_event(&mut tx, "product.updated", id).await?;
+        updated += 1;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(json!({"updated": updated})))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct RoundPricesRequest {
+    category_id: Option<i64>,
+    // exactly one of: the cents every price is raised to end in (99 makes 12.34 into 12.99), or
+    // the step every price is rounded to the nearest multiple of (100 makes 12.34 into 12.00)
+    to: Option<i64>,
+    nearest: Option<i64>,
+}
+
+// upper bound for `nearest`, i.e. rounding to whole 10000.00s at most
+const MAX_ROUNDING_STEP: i64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum PriceRounding {
+    EndIn(i64),
+    Nearest(i64),
+}
+
+impl PriceRounding {
+    fn from_request(payload: &RoundPricesRequest) -> Result<Self, AppError> {
+        match (payload.to, payload.nearest) {
+            (Some(to), None) if (0..100).contains(&to) => Ok(PriceRounding::EndIn(to)),
+            (Some(_), None) => Err(AppError::BadRequest("to must be between 0 and 99".into())),
+            (None, Some(step)) if (1..=MAX_ROUNDING_STEP).contains(&step) => Ok(PriceRounding::Nearest(step)),
+            (None, Some(_)) => Err(AppError::BadRequest(format!("nearest must be between 1 and {}", MAX_ROUNDING_STEP))),
+            _ => Err(AppError::BadRequest("exactly one of to or nearest is required".into())),
+        }
+    }
+
+    // None if the rounded price would overflow. Rounding never produces a price below the
+    // smallest valid one: a price that would round down to 0 goes up to one step instead
+    fn apply(self, price: i64) -> Option<i64> {
+        match self {
+            PriceRounding::EndIn(cents) => {
+                let candidate = price.checked_sub(price % 100)?.checked_add(cents)?;
+                let rounded = if candidate < price { candidate.checked_add(100)? } else { candidate };
+                Some(if rounded == 0 { 100 } else { rounded })
+            }
+            PriceRounding::Nearest(step) => {
+                let rounded = price.checked_add(step / 2)? / step * step;
+                Some(rounded.max(step))
+            }
+        }
+    }
+
+    fn reason(self) -> String {
+        match self {
+            PriceRounding::EndIn(cents) => format!("round-prices to .{:02}", cents),
+            PriceRounding::Nearest(step) => format!("round-prices nearest {}", step),
+        }
+    }
+}
+
+// rounds every matching price in one transaction, so a failure leaves all prices as they were
+async fn round_prices(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<RoundPricesRequest>) -> Result<Json<serde_json::Value>, AppError> {
+    auth.require_admin()?;
+    let rounding = PriceRounding::from_request(&payload)?;
+    let now = Utc::now().to_rfc3339();
+
+    let mut tx = begin_write(&state.pool).await?;
+    let rows = sqlx::query("SELECT id, price_cents FROM products WHERE deleted_at IS NULL AND (?1 IS NULL OR category_id = ?1)")
+        .bind(payload.category_id)
+        .fetch_all(tx.as_mut())
+        .await?;
+
+    let mut updated = 0;
+    for row in rows {
+        let id: i64 = row.get("id");
+        let old_price: i64 = row.get("price_cents");
+        let new_price = rounding.apply(old_price).ok_or_else(|| AppError::BadRequest(format!("rounded price of product {} is out of range", id)))?;
+        if new_price == old_price {
+            continue;
+        }
+
+        sqlx::query("UPDATE products SET price_cents = ?, updated_at = ? WHERE id = ?")
+            .bind(new_price)
+            .bind(&now)
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?;
+        sqlx::query("INSERT INTO price_history (product_id, old_price_cents, new_price_cents, reason, changed_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(id)
+            .bind(old_price)
+            .bind(new_price)
+            .bind(rounding.reason())
+            .bind(&now)
+            .execute(tx.as_mut())
+            .await?;
+        record_product_event(&mut tx, "product.updated", id).await?;
+        updated += 1;
+    }
+
+    tx.commit().await?;
+
+    Ok(Json(json!({"updated": updated})))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct SchedulePriceRequest {
+    price_cents: i64,
+    // RFC3339, must be in the future
+    effective_at: String,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ScheduledPrice {
+    id: i64,
+    product_id: i64,
+    price_cents: Money,
+    effective_at: String,
+    created_at: String,
+}
+
+// queues a price change; `scheduled_price_task` applies it once effective_at passes. Until then
+// reads and orders keep using the current price
+async fn schedule_price(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<SchedulePriceRequest>) -> Result<(StatusCode, Json<ScheduledPrice>), AppError> {
+    auth.require_admin()?;
+    if payload.price_cents <= 0 {
+        return Err(AppError::BadRequest("price_cents must be > 0".into()));
+    }
+    let effective_at = parse_timestamp_param("effective_at", Some(&payload.effective_at))?.unwrap_or_default();
+    let now = Utc::now().to_rfc3339();
+    if effective_at <= now {
+        return Err(AppError::BadRequest("effective_at must be in the future".into()));
+    }
+
+    let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    let scheduled_id: i64 = sqlx::query("INSERT INTO scheduled_prices (product_id, price_cents, effective_at, created_by, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id")
+        .bind(id)
+        .bind(payload.price_cents)
+        .bind(&effective_at)
+        .bind(auth.sub())
+        .bind(&now)
+        .fetch_one(&state.pool)
+        .await?
+        .get("id");
+    state.price_schedule.notify_one();
+
+    Ok((StatusCode::CREATED, Json(ScheduledPrice {
+        id: scheduled_id,
+        product_id: id,
+        price_cents: Money::from_cents(payload.price_cents),
+        effective_at,
+        created_at: now,
+    })))
+}
+
+const MAX_STOCK_FEED_ROWS: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockLevel {
+    sku: String,
+    stock: i32,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockFeedResult {
+    updated: usize,
+    // matched SKUs whose stock already had the reported value
+    unchanged: usize,
+    unmatched: usize,
+    unmatched_skus: Vec<String>,
+}
+
+// full inventory snapshot from a warehouse/ERP feed: sets absolute stock per variant SKU in one
+// transaction and records every change in variant_stock_history. Unknown SKUs are reported back
+// instead of failing the batch, since feeds routinely carry items this catalog doesn't sell
+async fn update_stock_levels(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(levels): Json<Vec<StockLevel>>) -> Result<Json<StockFeedResult>, AppError> {
+    auth.require_admin()?;
+    if levels.len() > MAX_STOCK_FEED_ROWS {
+        return Err(AppError::BadRequest(format!("at most {} stock levels per request", MAX_STOCK_FEED_ROWS)));
+    }
+    let mut seen = std::collections::HashSet::new();
+    for level in &levels {
+        validate_stock(&state, level.stock)?;
+        if !seen.insert(level.sku.as_str()) {
+            return Err(AppError::BadRequest(format!("duplicate sku {}", level.sku)));
+        }
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let mut result = StockFeedResult { updated: 0, unchanged: 0, unmatched: 0, unmatched_skus: Vec::new() };
+    let mut tx = begin_write(&state.pool).await?;
+    for level in levels {
+        let row = sqlx::query("SELECT id, stock FROM product_variants WHERE sku = ?")
+            .bind(&level.sku)
+            .fetch_optional(tx.as_mut())
+            .await?;
+        let Some(row) = row else {
+            result.unmatched += 1;
+            result.unmatched_skus.push(level.sku);
+            continue;
+        };
+        let variant_id: i64 = row.get("id");
+        let old_stock: i32 = row.get("stock");
+        if old_stock == level.stock {
+            result.unchanged += 1;
+            continue;
+        }
+
+        sqlx::query("UPDATE product_variants SET stock = ? WHERE id = ?")
+            .bind(level.stock)
+            .bind(variant_id)
+            .execute(tx.as_mut())
+            .await?;
+        sqlx::query("INSERT INTO variant_stock_history (variant_id, old_stock, new_stock, reason, changed_at) VALUES (?, ?, ?, 'inventory_feed', ?)")
+            .bind(variant_id)
+            .bind(old_stock)
+            .bind(level.stock)
+            .bind(&now)
+            .execute(tx.as_mut())
+            .await?;
+        result.updated += 1;
+    }
+    tx.commit().await?;
+
+    Ok(Json(result))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct MergeProducts {
+    keep_id: i64,
+    remove_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct MergeResult {
+    keep_id: i64,
+    removed_id: i64,
+    repointed_order_items: u64,
+    stock_added: i32,
+}
+
+// folds a duplicate product into another: its order lines and variants are repointed, its
+// stock is added to the kept product and it is soft-deleted, all in one transaction
+async fn merge_products(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<MergeProducts>) -> Result<Json<MergeResult>, AppError> {
+    auth.require_admin()?;
+    if payload.keep_id == payload.remove_id {
+        return Err(AppError::BadRequest("keep_id and remove_id must differ".into()));
+    }
+
+    let mut tx = begin_write(&state.pool).await?;
+
+    let mut keep_stock: i32 = 0;
+    let mut remove_stock: i32 = 0;
+    for id in [payload.keep_id, payload.remove_id] {
+        let row = sqlx::query("SELECT stock FROM products WHERE id = ? AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .ok_or_else(|| AppError::BadRequest(format!("product {} not found", id)))?;
+        if id == payload.remove_id {
+            remove_stock = row.get("stock");
+        } else {
+            keep_stock = row.get("stock");
+        }
+    }
+    // the combined stock has to fit both i32 and MAX_STOCK
+    let merged_stock = keep_stock.checked_add(remove_stock).ok_or_else(|| AppError::BadRequest("merged stock is out of range".into()))?;
+    validate_stock(&state, merged_stock)?;
+
+    let repointed = sqlx::query("UPDATE order_items SET product_id = ? WHERE product_id = ?")
+        .bind(payload.keep_id)
+        .bind(payload.remove_id)
+        .execute(tx.as_mut())
+        .await?
+        .rows_affected();
+    sqlx::query("UPDATE product_variants SET product_id = ? WHERE product_id = ?")
+        .bind(payload.keep_id)
+        .bind(payload.remove_id)
+        .execute(tx.as_mut())
+        .await?;
+    let now = Utc::now().to_rfc3339();
+    let delta = i64::from(remove_stock);
+    record_stock_change(&mut tx, payload.keep_id, delta, "merge_in", Some(&payload.remove_id.to_string())).await?;
+    record_stock_change(&mut tx, payload.remove_id, -delta, "merge_out", Some(&payload.keep_id.to_string())).await?;
+    sqlx::query("UPDATE products SET stock = stock + ?, updated_at = ? WHERE id = ?")
+        .bind(remove_stock)
+        .bind(&now)
+        .bind(payload.keep_id)
+        .execute(tx.as_mut())
+        .await?;
+    sqlx::query("UPDATE products SET stock = 0, deleted_at = ?, updated_at = ? WHERE id = ?")
+        .bind(&now)
+        .bind(&now)
+        .bind(payload.remove_id)
+        .execute(tx.as_mut())
+        .await?;
+    record_product_event(&mut tx, "product.updated", payload.keep_id).await?;
+    record_product_event(&mut tx, "product.deleted", payload.remove_id).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(MergeResult {
+        keep_id: payload.keep_id,
+        removed_id: payload.remove_id,
+        repointed_order_items: repointed,
+        stock_added: remove_stock,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockLedgerEntry {
+    id: i64,
+    delta: i64,
+    reason: String,
+    ref_id: Option<String>,
+    created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockLedger {
+    product_id: i64,
+    stock: i32,
+    ledger_stock: i64,
+    entries: Vec<StockLedgerEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ReconciledStock {
+    product_id: i64,
+    cached_stock: i64,
+    ledger_stock: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockReconciliationReport {
+    checked_at: String,
+    // when false the drift was only reported and products.stock was left as is
+    corrected: bool,
+    drifted: Vec<ReconciledStock>,
+}
+
+const MAX_CLIENT_ADJUSTMENT_ID_LEN: usize = 128;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockAdjustmentRequest {
+    delta: i32,
+    // set by warehouse clients that retry: a repeat with the same id is answered from the first
+    // attempt instead of applying the delta again
+    client_adjustment_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockAdjustmentResult {
+    product_id: i64,
+    delta: i32,
+    // stock right after this adjustment was applied
+    stock: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_adjustment_id: Option<String>,
+    // true when this is the stored answer to an earlier request with the same id
+    replayed: bool,
+}
+
+// relative stock change (a count correction, damaged goods, a delivery). 201 when applied; 200
+// with the original result when client_adjustment_id was seen before. Reusing an id for a
+// different product or delta is a 409, since it's almost certainly a client bug
+async fn adjust_stock(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<StockAdjustmentRequest>) -> Result<(StatusCode, Json<StockAdjustmentResult>), AppError> {
+    auth.require_admin()?;
+    if payload.delta == 0 {
+        return Err(AppError::BadRequest("delta must not be 0".into()));
+    }
+    let client_id = payload.client_adjustment_id.as_deref().map(str::trim);
+    if client_id.is_some_and(|c| c.is_empty() || c.len() > MAX_CLIENT_ADJUSTMENT_ID_LEN) {
+        return Err(AppError::BadRequest(format!("client_adjustment_id must be 1-{} characters", MAX_CLIENT_ADJUSTMENT_ID_LEN)));
+    }
+    let state: &AppState = &state;
+
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        if let Some(client_id) = client_id {
+            let previous = sqlx::query("SELECT product_id, delta, resulting_stock FROM stock_adjustments WHERE client_adjustment_id = ?")
+                .bind(client_id)
+                .fetch_optional(tx.as_mut())
+                .await?;
+            if let Some(previous) = previous {
+                if previous.get::<i64, _>("product_id") != id || previous.get::<i32, _>("delta") != payload.delta {
+                    return Err(AppError::Conflict("adjustment_id_reused"));
+                }
+                return Ok((StatusCode::OK, Json(StockAdjustmentResult {
+                    product_id: id,
+                    delta: payload.delta,
+                    stock: previous.get("resulting_stock"),
+                    client_adjustment_id: Some(client_id.to_string()),
+                    replayed: true,
+                })));
+            }
+        }
+
+        let current: i32 = sqlx::query("SELECT stock FROM products WHERE id = ? AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .ok_or(AppError::NotFound)?
+            .get("stock");
+        let stock = current.checked_add(payload.delta).ok_or_else(|| AppError::BadRequest("adjusted stock is out of range".into()))?;
+        validate_stock(state, stock)?;
+
+        record_stock_change(tx, id, i64::from(payload.delta), "adjustment", client_id).await?;
+        sqlx::query("UPDATE products SET stock = ?, updated_at = ? WHERE id = ?")
+            .bind(stock)
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?;
+        // the primary key also stops two concurrent first attempts from both applying
+        if let Some(client_id) = client_id {
+            sqlx::query("INSERT INTO stock_adjustments (client_adjustment_id, product_id, delta, resulting_stock, created_at) VALUES (?, ?, ?, ?, ?)")
+                .bind(client_id)
+                .bind(id)
+                .bind(payload.delta)
+                .bind(stock)
+                .bind(Utc::now().to_rfc3339())
+                .execute(tx.as_mut())
+                .await?;
+        }
+
+        Ok((StatusCode::CREATED, Json(StockAdjustmentResult {
+            product_id: id,
+            delta: payload.delta,
+            stock,
+            client_adjustment_id: client_id.map(str::to_owned),
+            replayed: false,
+        })))
+    })).await
+}
+
+async fn get_stock_ledger(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<StockLedger>, AppError> {
+    auth.require_admin()?;
+    let product = sqlx::query("SELECT stock FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let rows = sqlx::query("SELECT id, delta, reason, ref_id, created_at FROM stock_ledger WHERE product_id = ? ORDER BY id")
+        .bind(id)
+        .fetch_all(&state.pool)
+        .await?;
+
+    let entries: Vec<StockLedgerEntry> = rows
+        .into_iter()
+        .map(|r| StockLedgerEntry {
+            id: r.get("id"),
+            delta: r.get("delta"),
+            reason: r.get("reason"),
+            ref_id: r.get("ref_id"),
+            created_at: r.get("created_at"),
+        })
+        .collect();
+    let ledger_stock: i64 = entries.iter().map(|e| e.delta).sum();
+
+    Ok(Json(StockLedger {
+        product_id: id,
+        stock: product.get("stock"),
+        ledger_stock,
+        entries,
+    }))
+}
+
+// compares cached products.stock against the ledger sums and, when `correct` is set, rewrites
+// the cache for every product that has drifted; the report is kept for /health/ready
+async fn run_stock_reconciliation(state: &AppState, correct: bool) -> Result<StockReconciliationReport, AppError> {
+    let mut tx = begin_write(&state.pool).await?;
+
+    let rows = sqlx::query(
+        "SELECT p.id, p.stock, COALESCE(SUM(l.delta), 0) AS ledger_stock FROM products p LEFT JOIN stock_ledger l ON l.product_id = p.id \
+         GROUP BY p.id HAVING p.stock != COALESCE(SUM(l.delta), 0) ORDER BY p.id"
+    )
+    .fetch_all(tx.as_mut())
+    .await?;
+
+    let now = Utc::now().to_rfc3339();
+    let mut drifted = Vec::with_capacity(rows.len());
+    for r in rows {
+        let id: i64 = r.get("id");
+        let ledger_stock: i64 = r.get("ledger_stock");
+        if correct {
+            sqlx::query("UPDATE products SET stock = ?, updated_at = ? WHERE id = ?")
+                .bind(ledger_stock)
+                .bind(&now)
+                .bind(id)
+                .execute(tx.as_mut())
+                .await?;
+        }
+        drifted.push(ReconciledStock { product_id: id, cached_stock: r.get("stock"), ledger_stock });
+    }
+
+    tx.commit().await?;
+
+    if !drifted.is_empty() {
+        warn!(count = drifted.len(), corrected = correct, "stock drifted from ledger");
+    }
+    let report = StockReconciliationReport { checked_at: now, corrected: correct, drifted };
+    *state.last_stock_reconciliation.lock().unwrap() = Some(report.clone());
+    Ok(report)
+}
+
+async fn reconcile_stock(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<serde_json::Value>, AppError> {
+    auth.require_admin()?;
+    let report = run_stock_reconciliation(&state, true).await?;
+    Ok(Json(json!({"corrected": report.drifted})))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockSnapshot {
+    id: i64,
+    created_by: Option<String>,
+    created_at: String,
+    product_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct SnapshotRestoreResult {
+    snapshot_id: i64,
+    restored: usize,
+    unchanged: usize,
+    // products in the snapshot that have since been deleted; they are left alone
+    skipped: usize,
+}
+
+// records the stock of every live product, e.g. for a period-end count
+async fn create_stock_snapshot(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<(StatusCode, Json<StockSnapshot>), AppError> {
+    auth.require_admin()?;
+    let created_by = auth.sub();
+    let snapshot = with_tx(&state.pool, |tx| Box::pin(async move {
+        let now = Utc::now().to_rfc3339();
+        let id: i64 = sqlx::query("INSERT INTO stock_snapshots (created_by, created_at) VALUES (?, ?) RETURNING id")
+            .bind(created_by)
+            .bind(&now)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("id");
+        let product_count = sqlx::query("INSERT INTO stock_snapshot_items (snapshot_id, product_id, stock) SELECT ?, id, stock FROM products WHERE deleted_at IS NULL")
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?
+            .rows_affected() as i64;
+        Ok(StockSnapshot { id, created_by: created_by.map(str::to_owned), created_at: now, product_count })
+    })).await?;
+
+    info!(snapshot_id = snapshot.id, products = snapshot.product_count, "stock snapshot taken");
+    Ok((StatusCode::CREATED, Json(snapshot)))
+}
+
+async fn list_stock_snapshots(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<StockSnapshot>>, AppError> {
+    auth.require_admin()?;
+    let rows = sqlx::query(
+        "SELECT s.id, s.created_by, s.created_at, (SELECT COUNT(*) FROM stock_snapshot_items i WHERE i.snapshot_id = s.id) AS product_count \
+         FROM stock_snapshots s ORDER BY s.id DESC"
+    )
+        .fetch_all(state.read_pool())
+        .await?;
+    let snapshots = rows
+        .into_iter()
+        .map(|r| StockSnapshot {
+            id: r.get("id"),
+            created_by: r.get("created_by"),
+            created_at: r.get("created_at"),
+            product_count: r.get("product_count"),
+        })
+        .collect();
+    Ok(Json(Collection::complete(snapshots)))
+}
+
+// sets every product back to its snapshot stock in one transaction. Each change goes through the
+// ledger as a "snapshot_restore" entry, so the ledger still sums to the stock afterwards
+async fn restore_stock_snapshot(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<SnapshotRestoreResult>, AppError> {
+    auth.require_admin()?;
+    let result = with_tx(&state.pool, |tx| Box::pin(async move {
+        let exists = sqlx::query("SELECT id FROM stock_snapshots WHERE id = ?")
+            .bind(id)
+            .fetch_optional(tx.as_mut())
+            .await?;
+        if exists.is_none() {
+            return Err(AppError::NotFound);
+        }
+
+        let rows = sqlx::query(
+            "SELECT i.product_id, i.stock AS snapshot_stock, p.stock AS current_stock, p.deleted_at \
+             FROM stock_snapshot_items i LEFT JOIN products p ON p.id = i.product_id WHERE i.snapshot_id = ? ORDER BY i.product_id"
+        )
+            .bind(id)
+            .fetch_all(tx.as_mut())
+            .await?;
+
+        let now = Utc::now().to_rfc3339();
+        let ref_id = id.to_string();
+        let mut result = SnapshotRestoreResult { snapshot_id: id, restored: 0, unchanged: 0, skipped: 0 };
+        for r in rows {
+            let product_id: i64 = r.get("product_id");
+            let current: Option<i32> = r.get("current_stock");
+            let Some(current) = current.filter(|_| r.get::<Option<String>, _>("deleted_at").is_none()) else {
+                result.skipped += 1;
+                continue;
+            };
+            let target: i32 = r.get("snapshot_stock");
+            if current == target {
+                result.unchanged += 1;
+                continue;
+            }
+
+            record_stock_change(tx, product_id, i64::from(target) - i64::from(current), "snapshot_restore", Some(&ref_id)).await?;
+            sqlx::query("UPDATE products SET stock = ?, updated_at = ? WHERE id = ?")
+                .bind(target)
+                .bind(&now)
+                .bind(product_id)
+                .execute(tx.as_mut())
+                .await?;
+            result.restored += 1;
+        }
+        Ok(result)
+    })).await?;
+
+    info!(snapshot_id = id, restored = result.restored, "stock restored from snapshot");
+    Ok(Json(result))
+}
+
+// moves pending orders past their expiry to "expired" and puts their items back in stock. Each
+// order is its own transaction and the status change is conditional, so an order paid while the
+// sweep runs is left alone; only pending orders are ever touched
+async fn expire_pending_orders(state: &AppState, expiry: chrono::Duration) -> Result<u64, AppError> {
+    let cutoff = (Utc::now() - expiry).to_rfc3339();
+    let due: Vec<String> = sqlx::query("SELECT id FROM orders WHERE status = 'pending' AND created_at < ? ORDER BY created_at LIMIT ?")
+        .bind(&cutoff)
+        .bind(ORDER_EXPIRY_BATCH)
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .map(|r| r.get("id"))
+        .collect();
+
+    let mut expired = 0;
+    for order_id in &due {
+        let changed = with_tx(&state.pool, |tx| Box::pin(async move {
+            let updated = sqlx::query("UPDATE orders SET status = 'expired' WHERE id = ? AND status = 'pending'")
+                .bind(order_id)
+                .execute(tx.as_mut())
+                .await?
+                .rows_affected();
+            if updated == 0 {
+                return Ok(false);
+            }
+
+            let items = sqlx::query(
+                "SELECT i.product_id, i.variant_id, i.quantity, p.track_stock FROM order_items i JOIN products p ON p.id = i.product_id WHERE i.order_id = ?"
+            )
+                .bind(order_id)
+                .fetch_all(tx.as_mut())
+                .await?;
+            let now = Utc::now().to_rfc3339();
+            // one restock entry per product or variant, mirroring how place_order took the stock
+            let mut product_quantities: std::collections::BTreeMap<i64, i64> = std::collections::BTreeMap::new();
+            let mut variant_quantities: std::collections::BTreeMap<i64, i64> = std::collections::BTreeMap::new();
+            for item in items.iter().filter(|i| i.get::<bool, _>("track_stock")) {
+                let quantity = match item.get::<Option<i64>, _>("variant_id") {
+                    Some(variant_id) => variant_quantities.entry(variant_id).or_insert(0),
+                    None => product_quantities.entry(item.get("product_id")).or_insert(0),
+                };
+                *quantity += i64::from(item.get::<i32, _>("quantity"));
+            }
+            for (product_id, quantity) in product_quantities {
+                record_stock_change(tx, product_id, quantity, "order_expired", Some(order_id)).await?;
+                sqlx::query("UPDATE products SET stock = stock + ?, updated_at = ? WHERE id = ?")
+                    .bind(quantity)
+                    .bind(&now)
+                    .bind(product_id)
+                    .execute(tx.as_mut())
+                    .await?;
+            }
+            for (variant_id, quantity) in variant_quantities {
+                change_variant_stock(tx, variant_id, quantity, "order_expired", Some(order_id)).await?;
+            }
+
+            record_order_event(tx, order_id, "status_changed", Some("pending -> expired")).await?;
+            state.enqueue_event(tx, "order.expired", json!({"order_id": order_id})).await?;
+            Ok(true)
+        })).await?;
+        if changed {
+            expired += 1;
+        }
+    }
+    Ok(expired)
+}
+
+async fn order_expiry_task(state: Arc<AppState>, expiry: chrono::Duration) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(ORDER_EXPIRY_SWEEP_SECS));
+    loop {
+        ticker.tick().await;
+        match expire_pending_orders(&state, expiry).await {
+            Ok(0) => {}
+            Ok(expired) => info!(expired, "expired unpaid orders"),
+            Err(e) => error!("order expiry failed: {}", e),
+        }
+    }
+}
+
+// applies every scheduled price whose effective_at has passed, oldest first, so when several are
+// due for one product the latest wins. Each one is its own transaction and is marked applied
+// conditionally, so it is applied exactly once; a price for a product deleted in the meantime is
+// marked applied without changing anything
+async fn apply_due_prices(state: &AppState) -> Result<u64, AppError> {
+    let due: Vec<i64> = sqlx::query("SELECT id FROM scheduled_prices WHERE applied_at IS NULL AND effective_at <= ? ORDER BY effective_at, id")
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .map(|r| r.get("id"))
+        .collect();
+
+    let mut applied = 0;
+    for scheduled_id in due {
+        let changed = with_tx(&state.pool, |tx| Box::pin(async move {
+            let now = Utc::now().to_rfc3339();
+            let Some(scheduled) = sqlx::query("UPDATE scheduled_prices SET applied_at = ? WHERE id = ? AND applied_at IS NULL RETURNING product_id, price_cents")
+                .bind(&now)
+                .bind(scheduled_id)
+                .fetch_optional(tx.as_mut())
+                .await?
+            else {
+                return Ok(false);
+            };
+            let product_id: i64 = scheduled.get("product_id");
+            let new_price: i64 = scheduled.get("price_cents");
+
+            let Some(product) = sqlx::query("SELECT price_cents FROM products WHERE id = ? AND deleted_at IS NULL")
+                .bind(product_id)
+                .fetch_optional(tx.as_mut())
+                .await?
+            else {
+                return Ok(false);
+            };
+            let old_price: i64 = product.get("price_cents");
+            if old_price == new_price {
+                return Ok(false);
+            }
+
+            sqlx::query("UPDATE products SET price_cents = ?, updated_at = ? WHERE id = ?")
+                .bind(new_price)
+                .bind(&now)
+                .bind(product_id)
+                .execute(tx.as_mut())
+                .await?;
+            sqlx::query("INSERT INTO price_history (product_id, old_price_cents, new_price_cents, reason, changed_at) VALUES (?, ?, ?, ?, ?)")
+                .bind(product_id)
+                .bind(old_price)
+                .bind(new_price)
+                .bind(format!("scheduled #{}", scheduled_id))
+                .bind(&now)
+                .execute(tx.as_mut())
+                .await?;
+            record_product_event(tx, "product.updated", product_id).await?;
+            Ok(true)
+        })).await?;
+        if changed {
+            applied += 1;
+        }
+    }
+    Ok(applied)
+}
+
+// sleeps until the next pending effective_at (capped at SCHEDULED_PRICE_MAX_SLEEP_SECS) or until
+// a new price is scheduled, so prices change close to their effective time rather than on a
+// fixed sweep
+async fn scheduled_price_task(state: Arc<AppState>) {
+    loop {
+        match apply_due_prices(&state).await {
+            Ok(0) => {}
+            Ok(applied) => info!(applied, "applied scheduled prices"),
+            Err(e) => error!("applying scheduled prices failed: {}", e),
+        }
+
+        let next: Option<String> = sqlx::query("SELECT MIN(effective_at) AS next FROM scheduled_prices WHERE applied_at IS NULL")
+            .fetch_one(&state.pool)
+            .await
+            .map(|r| r.get("next"))
+            .unwrap_or(None);
+        let max_sleep = Duration::from_secs(SCHEDULED_PRICE_MAX_SLEEP_SECS);
+        let sleep = next
+            .and_then(|next| DateTime::parse_from_rfc3339(&next).ok())
+            .map(|next| (next.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or(Duration::ZERO).min(max_sleep))
+            .unwrap_or(max_sleep);
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            _ = state.price_schedule.notified() => {}
+        }
+    }
+}
+
+// runs every STOCK_RECONCILE_INTERVAL_SECS; drift is only logged unless AUTO_RECONCILE is on
+async fn stock_reconciliation_task(state: Arc<AppState>, every: Duration, auto_correct: bool) {
+    let mut ticker = tokio::time::interval(every);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = run_stock_reconciliation(&state, auto_correct).await {
+            error!("stock reconciliation failed: {}", e);
+        }
+    }
+}
+
+// readiness: the database must answer; the latest stock reconciliation is included as detail
+// and does not affect the status
+async fn health_ready(State(state): State<Arc<AppState>>) -> Response {
+    let database_ok = sqlx::query("SELECT 1").execute(&state.pool).await.is_ok();
+    let reconciliation = state.last_stock_reconciliation.lock().unwrap().clone();
+
+    // a schema other than this build's means a migration is missing (or a newer build ran);
+    // the service keeps answering, so that is reported as degraded rather than unavailable
+    let database_schema = pool_schema_version(&state.pool).await;
+    let replica_schema = match &state.replica {
+        Some(replica) => Some(pool_schema_version(replica).await),
+        None => None,
+    };
+    let schema_current = database_schema == Some(SCHEMA_VERSION) && replica_schema.is_none_or(|v| v == Some(SCHEMA_VERSION));
+
+    let status = if database_ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({
+        "status": if !database_ok { "unavailable" } else if !schema_current { "degraded" } else { "ready" },
+        "database": database_ok,
+        "schema": {
+            "expected_version": SCHEMA_VERSION,
+            "database_version": database_schema,
+            "replica_version": replica_schema.flatten(),
+            "current": schema_current,
+        },
+        "stock_reconciliation": reconciliation,
+    }))).into_response()
+}
+
+async fn pool_schema_version(pool: &SqlitePool) -> Option<i64> {
+    let mut conn = pool.acquire().await.ok()?;
+    schema_version(&mut conn).await.ok()
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ExportedProduct<'a> {
+    id: i64,
+    name: &'a str,
+    description: Option<&'a str>,
+    price_cents: i64,
+    stock: i32,
+    category_id: Option<i64>,
+    created_at: &'a str,
+    updated_at: &'a str,
+}
+
+// quote a CSV field when it contains a delimiter, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// streams the catalog row by row instead of buffering it: a task drives the sqlx row stream and
+// hands each encoded line to the response body through a small bounded channel, so memory stays
+// flat regardless of catalog size and a slow client simply slows the query down. Admin only:
+// the export includes scheduled products and raw stock levels
+async fn export_products(auth: MaybeAuth, Query(params): Query<ExportQuery>, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    auth.require_admin()?;
+    let csv = match params.format.as_deref().unwrap_or("csv") {
+        "csv" => true,
+        "json" => false,
+        other => return Err(AppError::BadRequest(format!("unsupported export format {}", other))),
+    };
+
+    let pool = state.read_pool().clone();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(64);
+
+    tokio::spawn(async move {
+        let header = if csv { "id,name,description,price_cents,stock,category_id,created_at,updated_at\n" } else { "[" };
+        if tx.send(Ok(header.to_string())).await.is_err() {
+            return;
+        }
+
+        let mut rows = sqlx::query("SELECT id, name, description, price_cents, stock, category_id, created_at, updated_at FROM products WHERE deleted_at IS NULL ORDER BY id")
+            .fetch(&pool);
+        let mut first = true;
+        loop {
+            let line = match rows.try_next().await {
+                Ok(Some(r)) => {
+                    let line = if csv {
+                        format!(
+                            "{},{},{},{},{},{},{},{}\n",
+                            r.get::<i64, _>("id"),
+                            csv_field(r.get("name")),
+                            csv_field(r.get::<Option<&str>, _>("description").unwrap_or("")),
+                            r.get::<i64, _>("price_cents"),
+                            r.get::<i32, _>("stock"),
+                            r.get::<Option<i64>, _>("category_id").map(|c| c.to_string()).unwrap_or_default(),
+                            r.get::<&str, _>("created_at"),
+                            r.get::<&str, _>("updated_at"),
+                        )
+                    } else {
+                        let item = ExportedProduct {
+                            id: r.get("id"),
+                            name: r.get("name"),
+                            description: r.get("description"),
+                            price_cents: r.get("price_cents"),
+                            stock: r.get("stock"),
+                            category_id: r.get("category_id"),
+                            created_at: r.get("created_at"),
+                            updated_at: r.get("updated_at"),
+                        };
+                        format!("{}{}", if first { "" } else { "," }, serde_json::to_string(&item).unwrap_or_default())
+                    };
+                    first = false;
+                    Ok(line)
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // headers are already sent, so the only way to signal failure is to abort the body
+                    error!("export failed: {}", e);
+                    Err(std::io::Error::other(e))
+                }
+            };
+            let failed = line.is_err();
+            if tx.send(line).await.is_err() || failed {
+                return;
+            }
+        }
+
+        if !csv {
+            let _ = tx.send(Ok("]".to_string())).await;
+        }
+    });
+
+    let (content_type, filename) = if csv { ("text/csv", "products.csv") } else { ("application/json", "products.json") };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    ).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct DeleteDryRun {
+    would_delete: bool,
+    referencing_order_items: i64,
+}
+
+async fn delete_product(Path(id): Path<i64>, Query(params): Query<DeleteQuery>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    // both the delete and the dry run (which reports order references) are staff-only, like /restore
+    auth.require_admin()?;
+    if params.dry_run {
+        // report the impact only; nothing is written
+        let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(&state.pool)
+            .await?
+            .is_some();
+        let referencing: i64 = sqlx::query("SELECT COUNT(*) AS n FROM order_items WHERE product_id = ?")
+            .bind(id)
+            .fetch_one(&state.pool)
+            .await?
+            .get("n");
+        // the row is only soft-deleted, so order_items referencing it no longer block the delete
+        return Ok(Json(DeleteDryRun { would_delete: exists, referencing_order_items: referencing }).into_response());
+    }
+
+    // soft delete: the row stays for order history and can be brought back via /restore
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        let now = Utc::now().to_rfc3339();
+        let deleted = sqlx::query("UPDATE products SET deleted_at = ?, updated_at = ? WHERE id = ? AND deleted_at IS NULL")
+            .bind(&now)
+            .bind(&now)
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?
+            .rows_affected();
+        if deleted > 0 {
+            record_product_event(tx, "product.deleted", id).await?;
+        }
+        Ok(())
+    })).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// without `force`, a bulk delete touching more products than this is refused
+const MAX_BULK_DELETE: i64 = 100;
+const MAX_BULK_DELETE_IDS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct BulkDeleteRequest {
+    category_id: Option<i64>,
+    ids: Option<Vec<i64>>,
+    #[serde(default)]
+    confirm: bool,
+    #[serde(default)]
+    force: bool,
+}
+
+// soft-deletes every live product matching exactly one filter (a category or an id list) in
+// one transaction; like DELETE /products/:id the rows stay restorable
+async fn bulk_delete_products(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<BulkDeleteRequest>) -> Result<Json<serde_json::Value>, AppError> {
+    auth.require_admin()?;
+    if !payload.confirm {
+        return Err(AppError::BadRequest("confirm must be true".into()));
+    }
+    let (filter, binds): (String, Vec<i64>) = match (payload.category_id, payload.ids) {
+        (Some(category_id), None) => ("category_id = ?".into(), vec![category_id]),
+        (None, Some(ids)) if ids.is_empty() => return Ok(Json(json!({"deleted": 0}))),
+        (None, Some(ids)) if ids.len() > MAX_BULK_DELETE_IDS => {
+            return Err(AppError::BadRequest(format!("at most {} ids per request", MAX_BULK_DELETE_IDS)));
+        }
+        (None, Some(ids)) => (format!("id IN ({})", vec!["?"; ids.len()].join(", ")), ids),
+        _ => return Err(AppError::BadRequest("exactly one of category_id or ids is required".into())),
+    };
+
+    let deleted = with_tx(&state.pool, |tx| Box::pin(async move {
+        let count_sql = format!("SELECT COUNT(*) AS n FROM products WHERE deleted_at IS NULL AND {}", filter);
+        let mut count = sqlx::query(&count_sql);
+        for value in &binds {
+            count = count.bind(value);
+        }
+        let matching: i64 = count.fetch_one(tx.as_mut()).await?.get("n");
+        if matching > MAX_BULK_DELETE && !payload.force {
+            return Err(AppError::BadRequest(format!("would delete {} products, more than {}; pass force: true to proceed", matching, MAX_BULK_DELETE)));
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let update_sql = format!("UPDATE products SET deleted_at = ?, updated_at = ? WHERE deleted_at IS NULL AND {} RETURNING id", filter);
+        let mut update = sqlx::query(&update_sql).bind(&now).bind(&now);
+        for value in &binds {
+            update = update.bind(value);
+        }
+        let deleted = update.fetch_all(tx.as_mut()).await?;
+        for row in &deleted {
+            record_product_event(tx, "product.deleted", row.get("id")).await?;
+        }
+        Ok(deleted.len())
+    })).await?;
+
+    info!(deleted, "bulk product delete");
+    Ok(Json(json!({"deleted": deleted})))
+}
+
+// undoes a delete; with UNIQUE_PRODUCT_NAMES this fails with 409 when a live product has taken
+// the name in the meantime. Webhook subscribers see the product come back as product.created
+async fn restore_product(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Product>, AppError> {
+    auth.require_admin()?;
+    let sql = format!("UPDATE products SET deleted_at = NULL, updated_at = ? WHERE id = ? AND deleted_at IS NOT NULL RETURNING {}", PRODUCT_COLUMNS);
+    let sql = sql.as_str();
+    let product = with_tx(&state.pool, |tx| Box::pin(async move {
+        let product = sqlx::query_as::<_, Product>(sql)
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .fetch_optional(tx.as_mut())
+            .await
+            .map_err(duplicate_name_error)?
+            .ok_or(AppError::NotFound)?;
+        record_product_event(tx, "product.created", id).await?;
+        Ok(product)
+    })).await?;
+
+    Ok(Json(product.for_viewer(&auth)))
+}
+
+// how related products are chosen; add variants here (e.g. co-purchases from order_items)
+// and switch `RELATED_STRATEGY` to change what the related endpoint returns
+#[derive(Debug, Clone, Copy)]
+enum RelatedStrategy {
+    SameCategory,
+}
+
+const RELATED_STRATEGY: RelatedStrategy = RelatedStrategy::SameCategory;
+
+impl RelatedStrategy {
+    async fn find(self, pool: &SqlitePool, source: &Product, limit: i64, admin: bool) -> Result<Vec<Product>, AppError> {
+        match self {
+            RelatedStrategy::SameCategory => {
+                let Some(category_id) = source.category_id else {
+                    return Ok(Vec::new());
+                };
+                let sql = format!(
+                    "SELECT {} FROM products WHERE deleted_at IS NULL AND category_id = ?1 AND id != ?2 AND {} ORDER BY stock DESC, id DESC LIMIT ?5",
+                    PRODUCT_COLUMNS, available_sql("", 3, 4)
+                );
+                let products = sqlx::query_as::<_, Product>(&sql)
+                    .bind(category_id)
+                    .bind(source.id)
+                    .bind(admin)
+                    .bind(Utc::now().to_rfc3339())
+                    .bind(limit)
+                    .fetch_all(pool)
+                    .await?;
+
+                Ok(products.into_iter().map(Product::without_attribution).collect())
+            }
+        }
+    }
+}
+
+// products that aren't available yet (or any more) are neither a source nor a suggestion for
+// non-admins, same as GET /products/:id
+async fn related_products(Path(id): Path<i64>, auth: MaybeAuth, Query(params): Query<RelatedQuery>, State(state): State<Arc<AppState>>) -> Result<Json<Collection<Product>>, AppError> {
+    let limit = params.limit.unwrap_or(10).clamp(1, 50);
+
+    let source = fetch_product(state.read_pool().clone(), id, auth.is_admin()).await?.ok_or(AppError::NotFound)?;
+
+    Ok(Json(Collection::complete(RELATED_STRATEGY.find(state.read_pool(), &source, limit, auth.is_admin()).await?)))
+}
+
+async fn frequently_bought_together(Path(id): Path<i64>, auth: MaybeAuth, Query(params): Query<RelatedQuery>, State(state): State<Arc<AppState>>) -> Result<Json<Collection<BoughtTogether>>, AppError> {
+    let limit = params.limit.unwrap_or(10).clamp(1, 50);
+
+    if fetch_product(state.read_pool().clone(), id, auth.is_admin()).await?.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    // pair every order line of the product with the other lines of the same order; the join
+    // on products drops lines whose product is gone, soft-deleted or outside its window
+    let rows = sqlx::query(&format!(
+        "SELECT p.id, p.name, p.description, p.price_cents, p.stock, p.track_stock, p.category_id, p.created_by, p.updated_by, p.available_from, p.available_until, p.created_at, p.updated_at, p.metadata, COUNT(DISTINCT b.order_id) AS times_bought_together \
+         FROM order_items a \
+         JOIN order_items b ON b.order_id = a.order_id AND b.product_id != a.product_id \
+         JOIN products p ON p.id = b.product_id AND p.deleted_at IS NULL AND {} \
+         WHERE a.product_id = ?1 \
+         GROUP BY p.id ORDER BY times_bought_together DESC, p.id LIMIT ?4",
+        available_sql("p.", 2, 3)
+    ))
+    .bind(id)
+    .bind(auth.is_admin())
+    .bind(Utc::now().to_rfc3339())
+    .bind(limit)
+    .fetch_all(state.read_pool())
+    .await?;
+
+    let items = rows
+        .iter()
+        .map(|r| Ok(BoughtTogether {
+            product: Product::from_row(r)?.without_attribution(),
+            times_bought_together: r.get("times_bought_together"),
+        }))
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+    Ok(Json(Collection::complete(items)))
+}
+
+// parse an RFC3339 query parameter and normalize it to the UTC format used for stored timestamps,
+// so that range filters can compare the TEXT columns directly
+fn parse_timestamp_param(name: &str, value: Option<&str>) -> Result<Option<String>, AppError> {
+    match value {
+        Some(v) => DateTime::parse_from_rfc3339(v)
+            .map(|dt| Some(dt.with_timezone(&Utc).to_rfc3339()))
+            .map_err(|_| AppError::BadRequest(format!("{} must be an RFC3339 timestamp", name))),
+        None => Ok(None),
+    }
+}
+
+// If-Unmodified-Since: fail with 412 when the product changed after the given HTTP date.
+// HTTP dates only carry whole seconds, so the stored timestamp is compared at that precision;
+// an unparseable header is ignored, as RFC 9110 requires
+fn check_unmodified_since(headers: &HeaderMap, updated_at: &str) -> Result<(), AppError> {
+    let Some(since) = headers
+        .get(header::IF_UNMODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+    else {
+        return Ok(());
+    };
+    let updated = DateTime::parse_from_rfc3339(updated_at).map_err(|_| AppError::InternalError)?;
+    if updated.timestamp() > since.timestamp() {
+        return Err(AppError::PreconditionFailed);
+    }
+    Ok(())
+}
+
+// with UNIQUE_PRODUCT_NAMES the partial unique index on products.name is the only source of
+// this violation; everything else stays a plain database error
+fn duplicate_name_error(e: sqlx::Error) -> AppError {
+    if let sqlx::Error::Database(db) = &e && db.is_unique_violation() && (db.message().contains("products.name") || db.message().contains("categories.name")) {
+        return AppError::Conflict("duplicate_name");
+    }
+    AppError::DbError(e)
+}
+
+// lengths are counted in characters, not bytes, so the limits mean the same for any script
+fn validate_product_text(state: &AppState, name: Option<&str>, description: Option<&str>) -> Result<(), AppError> {
+    if let Some(name) = name && name.chars().count() > state.max_name_len {
+        return Err(AppError::BadRequest(format!("name must be at most {} characters", state.max_name_len)));
+    }
+    if let Some(description) = description && description.chars().count() > state.max_description_len {
+        return Err(AppError::BadRequest(format!("description must be at most {} characters", state.max_description_len)));
+    }
+    Ok(())
+}
+
+const MAX_METADATA_BYTES: usize = 16 * 1024;
+
+// metadata has to be a JSON object and stay under MAX_METADATA_BYTES once serialized; returns
+// the text stored in products.metadata
+fn validate_metadata(metadata: Option<&serde_json::Value>) -> Result<Option<String>, AppError> {
+    let Some(metadata) = metadata else {
+        return Ok(None);
+    };
+    if !metadata.is_object() {
+        return Err(AppError::BadRequest("metadata must be a JSON object".into()));
+    }
+    let text = metadata.to_string();
+    if text.len() > MAX_METADATA_BYTES {
+        return Err(AppError::BadRequest(format!("metadata must be at most {} bytes", MAX_METADATA_BYTES)));
+    }
+    Ok(Some(text))
+}
+
+fn parse_metadata(raw: Option<String>) -> Option<serde_json::Value> {
+    raw.and_then(|text| serde_json::from_str(&text).ok())
+}
+
+fn validate_stock(state: &AppState, stock: i32) -> Result<(), AppError> {
+    if !(0..=state.max_stock).contains(&stock) {
+        return Err(AppError::BadRequest(format!("stock must be between 0 and {}", state.max_stock)));
+    }
+    Ok(())
+}
+
+fn validate_availability_window(from: Option<&str>, until: Option<&str>) -> Result<(), AppError> {
+    if let (Some(from), Some(until)) = (from, until) && from >= until {
+        return Err(AppError::BadRequest("available_from must be before available_until".into()));
+    }
+    Ok(())
+}
+
+async fn product_sales(Path(id): Path<i64>, Query(params): Query<SalesQuery>, State(state): State<Arc<AppState>>) -> Result<Json<ProductSalesResponse>, AppError> {
+    let from = parse_timestamp_param("from", params.from.as_deref())?;
+    let to = parse_timestamp_param("to", params.to.as_deref())?;
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let exists = sqlx::query("SELECT id FROM products WHERE id = ? AND deleted_at IS NULL")
+        .bind(id)
+        .fetch_optional(state.read_pool())
+        .await?;
+    if exists.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    let rows = sqlx::query(
+        "SELECT oi.order_id, oi.quantity, oi.unit_price_cents, o.created_at FROM order_items oi JOIN orders o ON o.id = oi.order_id \
+         WHERE oi.product_id = ?1 AND (?2 IS NULL OR o.created_at >= ?2) AND (?3 IS NULL OR o.created_at <= ?3) \
+         ORDER BY o.created_at DESC, oi.id DESC LIMIT ?4 OFFSET ?5"
+    )
+    .bind(id)
+    .bind(&from)
+    .bind(&to)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(state.read_pool())
+    .await?;
+
+    let items: Vec<ProductSale> = rows
+        .into_iter()
+        .map(|r| ProductSale {
+            order_id: r.get("order_id"),
+            quantity: r.get("quantity"),
+            unit_price_cents: r.get("unit_price_cents"),
+            ordered_at: r.get("created_at"),
+        })
+        .collect();
+
+    // totals cover the whole filtered range, not just the current page
+    let totals = sqlx::query(
+        "SELECT COUNT(*) AS line_items, COALESCE(SUM(oi.quantity), 0) AS units_sold, COALESCE(SUM(oi.quantity * oi.unit_price_cents), 0) AS revenue_cents \
+         FROM order_items oi JOIN orders o ON o.id = oi.order_id \
+         WHERE oi.product_id = ?1 AND (?2 IS NULL OR o.created_at >= ?2) AND (?3 IS NULL OR o.created_at <= ?3)"
+    )
+    .bind(id)
+    .bind(&from)
+    .bind(&to)
+    .fetch_one(state.read_pool())
+    .await?;
+
+    let summary = SalesSummary {
+        line_items: totals.get("line_items"),
+        units_sold: totals.get("units_sold"),
+        revenue_cents: totals.get("revenue_cents"),
+    };
+
+    // the cursor is simply the offset of the next page
+    let next_cursor = (offset + limit < summary.line_items).then(|| (offset + limit).to_string());
+    let page = Collection { items, next_cursor, total: summary.line_items };
+    Ok(Json(ProductSalesResponse { product_id: id, page, summary, limit, offset }))
+}
+
+// orders that never turned into a sale: cancelled, expired unpaid, or refunded in full
+const COUNTED_SALE: &str = "o.status NOT IN ('cancelled', 'expired') \
+     AND NOT EXISTS (SELECT 1 FROM refunds r WHERE r.order_id = o.id GROUP BY r.order_id HAVING SUM(r.amount_cents) >= o.total_cents)";
+
+// products ranked by units sold in the window (by order created_at, both bounds inclusive), ties
+// broken by revenue; soft-deleted products and, for non-admins, products outside their
+// availability window are left out. Revenue figures are only returned to admins
+async fn bestsellers(auth: MaybeAuth, Query(params): Query<SalesQuery>, State(state): State<Arc<AppState>>) -> Result<Json<Collection<Bestseller>>, AppError> {
+    let from = parse_timestamp_param("from", params.from.as_deref())?;
+    let to = parse_timestamp_param("to", params.to.as_deref())?;
+    let limit = params.limit.unwrap_or(10).clamp(1, 100);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let window = format!(
+        "FROM order_items oi JOIN orders o ON o.id = oi.order_id JOIN products p ON p.id = oi.product_id \
+         WHERE p.deleted_at IS NULL AND (?1 IS NULL OR o.created_at >= ?1) AND (?2 IS NULL OR o.created_at <= ?2) AND {} AND {}",
+        available_sql("p.", 3, 4), COUNTED_SALE
+    );
+    let now = Utc::now().to_rfc3339();
+    let rows = sqlx::query(&format!(
+        "SELECT oi.product_id, p.name, SUM(oi.quantity) AS units_sold, SUM(oi.quantity * oi.unit_price_cents) AS revenue_cents {} \
+         GROUP BY oi.product_id ORDER BY units_sold DESC, revenue_cents DESC, oi.product_id LIMIT ?5 OFFSET ?6",
+        window
+    ))
+    .bind(&from)
+    .bind(&to)
+    .bind(auth.is_admin())
+    .bind(&now)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(state.read_pool())
+    .await?;
+
+    let items = rows
+        .into_iter()
+        .map(|r| Bestseller {
+            product_id: r.get("product_id"),
+            name: r.get("name"),
+            units_sold: r.get("units_sold"),
+            revenue_cents: auth.is_admin().then(|| r.get("revenue_cents")),
+        })
+        .collect();
+
+    let total: i64 = sqlx::query(&format!("SELECT COUNT(DISTINCT oi.product_id) AS total {}", window))
+        .bind(&from)
+        .bind(&to)
+        .bind(auth.is_admin())
+        .bind(&now)
+        .fetch_one(state.read_pool())
+        .await?
+        .get("total");
+
+    let next_cursor = (offset + limit < total).then(|| (offset + limit).to_string());
+    Ok(Json(Collection { items, next_cursor, total }))
+}
+
+async fn create_order(auth: MaybeAuth, ConnectInfo(peer): ConnectInfo<SocketAddr>, State(state): State<Arc<AppState>>, Json(payload): Json<CreateOrder>) -> Result<(StatusCode, [(header::HeaderName, String); 1], Json<OrderResponse>), AppError> {
+    let client_ip = peer.ip().to_string();
+    check_order_rate_limit(&state, auth.sub(), &client_ip).await?;
+    let order = place_order(&state, &payload, auth.sub(), Some(&client_ip)).await?;
+    Ok((StatusCode::CREATED, state.location("orders", &order.id), Json(order)))
+}
+
+// CUSTOMER_ORDER_LIMIT orders per CUSTOMER_ORDER_WINDOW_SECS, counted from the orders table:
+// per customer (the token subject) when authenticated, per client IP for anonymous orders. The
+// count is read before the order transaction, so concurrent requests can overshoot slightly
+async fn check_order_rate_limit(state: &AppState, customer_id: Option<&str>, client_ip: &str) -> Result<(), AppError> {
+    let Some(limit) = state.customer_order_limit else {
+        return Ok(());
+    };
+    let since = (Utc::now() - chrono::Duration::seconds(state.customer_order_window_secs)).to_rfc3339();
+    let recent: i64 = match customer_id {
+        Some(customer_id) => sqlx::query("SELECT COUNT(*) AS n FROM orders WHERE customer_id = ? AND created_at > ?")
+            .bind(customer_id)
+            .bind(&since)
+            .fetch_one(&state.pool)
+            .await?
+            .get("n"),
+        None => sqlx::query("SELECT COUNT(*) AS n FROM orders WHERE customer_id IS NULL AND client_ip = ? AND created_at > ?")
+            .bind(client_ip)
+            .bind(&since)
+            .fetch_one(&state.pool)
+            .await?
+            .get("n"),
+    };
+    if recent >= limit {
+        return Err(AppError::TooManyRequests);
+    }
+    Ok(())
+}
+
+// validates, prices and writes one order (items, stock decrements, ledger and timeline) in a
+// single transaction; shared by the single and bulk order endpoints
+#[tracing::instrument(name = "place_order", skip_all, fields(items = payload.items.len(), order_id))]
+async fn place_order(state: &AppState, payload: &CreateOrder, customer_id: Option<&str>, client_ip: Option<&str>) -> Result<OrderResponse, AppError> {
+    if payload.items.is_empty() {
+        return Err(AppError::BadRequest("order must contain at least one item".into()));
+    }
+    // a single line can never need more than MAX_STOCK units, which also keeps the
+    // quantity * price math far from overflowing
+    if let Some(item) = payload.items.iter().find(|i| i.quantity < 1 || i.quantity > state.max_stock) {
+        return Err(AppError::BadRequest(format!("quantity for product {} must be between 1 and {}", item.product_id, state.max_stock)));
+    }
+    if payload.adjustments.len() > MAX_ORDER_ADJUSTMENTS {
+        return Err(AppError::BadRequest(format!("at most {} adjustments per order", MAX_ORDER_ADJUSTMENTS)));
+    }
+    if payload.shipping_address_id.is_some() && customer_id.is_none() {
+        return Err(AppError::BadRequest("shipping_address_id requires a signed-in customer".into()));
+    }
+    for adjustment in &payload.adjustments {
+        let label_len = adjustment.label.trim().chars().count();
+        if label_len == 0 || label_len > MAX_ADJUSTMENT_LABEL_LEN {
+            return Err(AppError::BadRequest(format!("adjustment label must be 1-{} characters", MAX_ADJUSTMENT_LABEL_LEN)));
+        }
+        if adjustment.amount_cents <= 0 {
+            return Err(AppError::BadRequest("adjustment amount_cents must be > 0".into()));
+        }
+    }
+    // every statement below runs on `tx`; any `?` or early return rolls back the order row, its
+    // items and all stock decrements together
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        let mut subtotal_cents = Money::ZERO;
+        // unit price chosen for each line, reused when the order items are written
+        let mut unit_prices: Vec<Money> = Vec::with_capacity(payload.items.len());
+
+        // price tiers apply to the total quantity of a product across all of its (non-variant) lines
+        let mut product_quantities: std::collections::HashMap<i64, i32> = std::collections::HashMap::new();
+        for item in payload.items.iter().filter(|i| i.variant_id.is_none()) {
+            let total = product_quantities.entry(item.product_id).or_insert(0);
+            *total = total.saturating_add(item.quantity);
+        }
+
+        let lines = load_order_lines(tx.as_mut(), &payload.items).await?;
+        // stock is read once for the whole order, so lines naming the same product or variant are
+        // checked against what is left after the earlier ones, as in `preview_order_stock`
+        let mut remaining: std::collections::HashMap<(i64, Option<i64>), i32> = std::collections::HashMap::new();
+        for (item, line) in payload.items.iter().zip(&lines) {
+            let mut unit_price = line.price_cents;
+            if item.variant_id.is_none() {
+                let quantity = product_quantities[&item.product_id];
+                let tier_price = sqlx::query_scalar!(r#"SELECT MIN(price_cents) AS "price_cents?: Money" FROM price_tiers WHERE product_id = ? AND min_quantity <= ?"#, item.product_id, quantity)
+                    .fetch_one(tx.as_mut())
+                    .await?;
+                if let Some(tier_price) = tier_price {
+                    unit_price = unit_price.min(tier_price);
+                }
+            }
+
+            let left = remaining.entry((item.product_id, item.variant_id)).or_insert(line.stock);
+            *left = left.saturating_sub(item.quantity);
+            if line.track_stock && *left < 0 {
+                return Err(match item.variant_id {
+                    Some(variant_id) => AppError::BadRequest(format!("not enough stock for variant {} of product {}", variant_id, item.product_id)),
+                    None => AppError::BadRequest(format!("not enough stock for product {}", item.product_id)),
+                });
+            }
+
+            subtotal_cents = unit_price
+                .checked_mul(i64::from(item.quantity))
+                .and_then(|line_total| subtotal_cents.checked_add(line_total))
+                .ok_or_else(order_total_out_of_range)?;
+            unit_prices.push(unit_price);
+        }
+
+        // adjustments are charged as-is: tax only applies to the product subtotal
+        let adjustments_cents = Money::checked_sum(payload.adjustments.iter().map(|a| Money::from_cents(a.amount_cents))).ok_or_else(order_total_out_of_range)?;
+        let tax_cents = order_tax_cents(subtotal_cents, state.tax_rate_bps).ok_or_else(order_total_out_of_range)?;
+        let total_cents = Money::checked_sum([subtotal_cents, adjustments_cents, tax_cents]).ok_or_else(order_total_out_of_range)?;
+        if let Some(minimum) = state.min_order_total_cents {
+            let counted = if state.min_order_includes_adjustments {
+                subtotal_cents.checked_add(adjustments_cents).ok_or_else(order_total_out_of_range)?
+            } else {
+                subtotal_cents
+            };
+            if counted < minimum {
+                return Err(AppError::BadRequest(format!("order total below minimum of {} cents", minimum.cents())));
+            }
+        }
+
+        let order_id = Uuid::new_v4().to_string();
+        tracing::Span::current().record("order_id", order_id.as_str());
+        let now = Utc::now().to_rfc3339();
+        // the next number is taken inside the INSERT itself, which runs under SQLite's write lock,
+        // so concurrent orders can't draw the same one (and the unique index would reject it anyway)
+        let order_number = sqlx::query_scalar!(
+            r#"INSERT INTO orders (id, order_number, customer_id, client_ip, subtotal_cents, tax_cents, total_cents, created_at)
+               VALUES (?, (SELECT COALESCE(MAX(order_number), 0) + 1 FROM orders), ?, ?, ?, ?, ?, ?) RETURNING order_number AS "order_number!""#,
+            order_id, customer_id, client_ip, subtotal_cents, tax_cents, total_cents, now
+        )
+            .fetch_one(tx.as_mut())  // Use tx.as_mut() for transaction executor
+            .await?;
+
+        // copied rather than referenced, so editing or deleting the address later leaves the order as placed
+        if let Some(address_id) = payload.shipping_address_id {
+            let copied = sqlx::query!(
+                "INSERT INTO order_addresses (order_id, kind, address_id, line1, line2, city, region, postal_code, country) \
+                 SELECT ?, 'shipping', id, line1, line2, city, region, postal_code, country FROM customer_addresses WHERE id = ? AND customer_id = ? AND type = 'shipping'",
+                order_id, address_id, customer_id
+            )
+                .execute(tx.as_mut())
+                .await?
+                .rows_affected();
+            if copied == 0 {
+                return Err(AppError::BadRequest(format!("shipping address {} not found", address_id)));
+            }
+        }
+
+        for adjustment in &payload.adjustments {
+            let label = adjustment.label.trim();
+            sqlx::query!("INSERT INTO order_adjustments (order_id, label, amount_cents) VALUES (?, ?, ?)", order_id, label, adjustment.amount_cents)
+                .execute(tx.as_mut())
+                .await?;
+        }
+
+        // stock is taken once per product or variant, however many lines name it, so the order
+        // leaves exactly one journal entry for each (see `record_stock_change`)
+        let mut product_quantities: std::collections::BTreeMap<i64, i32> = std::collections::BTreeMap::new();
+        let mut variant_quantities: std::collections::BTreeMap<i64, i32> = std::collections::BTreeMap::new();
+        for ((item, unit_price), line) in payload.items.iter().zip(unit_prices).zip(&lines) {
+            sqlx::query!(
+                "INSERT INTO order_items (order_id, product_id, variant_id, quantity, unit_price_cents) VALUES (?, ?, ?, ?, ?)",
+                order_id, item.product_id, item.variant_id, item.quantity, unit_price
+            )
+                .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
+                .await?;
+
+            // untracked products keep whatever stock they have and get no ledger entry
+            if !line.track_stock {
+                continue;
+            }
+            let quantity = match item.variant_id {
+                Some(variant_id) => variant_quantities.entry(variant_id).or_insert(0),
+                None => product_quantities.entry(item.product_id).or_insert(0),
+            };
+            *quantity = quantity.saturating_add(item.quantity);
+        }
+        for (product_id, quantity) in product_quantities {
+            record_stock_change(tx, product_id, -i64::from(quantity), "order", Some(&order_id)).await?;
+            sqlx::query!("UPDATE products SET stock = stock - ?, updated_at = ? WHERE id = ?", quantity, now, product_id)
+                .execute(tx.as_mut())  // Use tx.as_mut() for transaction executor
+                .await?;
+        }
+        for (variant_id, quantity) in variant_quantities {
+            change_variant_stock(tx, variant_id, -i64::from(quantity), "order", Some(&order_id)).await?;
+        }
+
+        record_order_event(tx, &order_id, "created", Some(&format!("total_cents={}", total_cents.cents()))).await?;
+
+        state.enqueue_event(tx, "order.created", json!({"order_id": order_id, "total_cents": total_cents})).await?;
+
+        let expires_at = state.order_expires_at("pending", &now);
+        Ok(OrderResponse { id: order_id, order_number: format_order_number(order_number), subtotal_cents, adjustments_cents, tax_cents, total_cents, expires_at })
+    })).await
+}
+
+// stock and list price of what one order line points at: the variant when it names one,
+// otherwise the product
+struct OrderLineStock {
+    stock: i32,
+    price_cents: Money,
+    // the product's flag, which also covers its variants
+    track_stock: bool,
+}
+
+// looks up every line of an order in two queries (products, variants) instead of one per line,
+// returning them in line order. Unknown and soft-deleted products are rejected here, so order
+// placement and the stock preview agree on what can be ordered
+async fn load_order_lines(conn: &mut SqliteConnection, items: &[OrderItemRequest]) -> Result<Vec<OrderLineStock>, AppError> {
+    type Found = (i32, Money, bool, bool);
+
+    let mut product_ids: Vec<i64> = items.iter().filter(|i| i.variant_id.is_none()).map(|i| i.product_id).collect();
+    product_ids.sort_unstable();
+    product_ids.dedup();
+    let mut variant_ids: Vec<i64> = items.iter().filter_map(|i| i.variant_id).collect();
+    variant_ids.sort_unstable();
+    variant_ids.dedup();
+
+    let mut products: std::collections::HashMap<i64, Found> = std::collections::HashMap::new();
+    if !product_ids.is_empty() {
+        let sql = format!("SELECT id, stock, price_cents, track_stock, deleted_at FROM products WHERE id IN ({})", vec!["?"; product_ids.len()].join(", "));
+        let mut query = sqlx::query(&sql);
+        for id in &product_ids {
+            query = query.bind(id);
+        }
+        for r in query.fetch_all(&mut *conn).await? {
+            products.insert(r.get("id"), (r.get("stock"), r.get("price_cents"), r.get("track_stock"), r.get::<Option<String>, _>("deleted_at").is_some()));
+        }
+    }
+
+    // keyed by (variant, product) so a variant listed under the wrong product isn't found
+    let mut variants: std::collections::HashMap<(i64, i64), Found> = std::collections::HashMap::new();
+    if !variant_ids.is_empty() {
+        let sql = format!(
+            "SELECT v.id, v.product_id, v.stock, v.price_cents, p.track_stock, p.deleted_at FROM product_variants v JOIN products p ON p.id = v.product_id WHERE v.id IN ({})",
+            vec!["?"; variant_ids.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for id in &variant_ids {
+            query = query.bind(id);
+        }
+        for r in query.fetch_all(&mut *conn).await? {
+            variants.insert((r.get("id"), r.get("product_id")), (r.get("stock"), r.get("price_cents"), r.get("track_stock"), r.get::<Option<String>, _>("deleted_at").is_some()));
+        }
+    }
+
+    items
+        .iter()
+        .map(|item| {
+            let found = match item.variant_id {
+                Some(variant_id) => variants.get(&(variant_id, item.product_id)),
+                None => products.get(&item.product_id),
+            };
+            let &(stock, price_cents, track_stock, deleted) = match (found, item.variant_id) {
+                (Some(found), _) => found,
+                (None, Some(variant_id)) => return Err(AppError::BadRequest(format!("variant {} of product {} not found", variant_id, item.product_id))),
+                (None, None) => return Err(AppError::BadRequest(format!("product {} not found", item.product_id))),
+            };
+            // soft-deleted products still have their rows (and variants) but can't be ordered
+            if deleted {
+                return Err(AppError::BadRequest(format!("product {} is not available", item.product_id)));
+            }
+            Ok(OrderLineStock { stock, price_cents, track_stock })
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockPreviewRequest {
+    items: Vec<OrderItemRequest>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct StockPreviewLine {
+    product_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant_id: Option<i64>,
+    current_stock: i32,
+    after_stock: i32,
+    sufficient: bool,
+}
+
+// read-only: reports what create_order would leave in stock. Lines for the same product or
+// variant are cumulative, so after_stock of the last one is the final level
+async fn preview_order_stock(State(state): State<Arc<AppState>>, Json(payload): Json<StockPreviewRequest>) -> Result<Json<Collection<StockPreviewLine>>, AppError> {
+    if payload.items.is_empty() {
+        return Err(AppError::BadRequest("order must contain at least one item".into()));
+    }
+    if let Some(item) = payload.items.iter().find(|i| i.quantity < 1 || i.quantity > state.max_stock) {
+        return Err(AppError::BadRequest(format!("quantity for product {} must be between 1 and {}", item.product_id, state.max_stock)));
+    }
+
+    let mut conn = state.read_pool().acquire().await?;
+    let lines = load_order_lines(&mut conn, &payload.items).await?;
+
+    let mut remaining: std::collections::HashMap<(i64, Option<i64>), i32> = std::collections::HashMap::new();
+    let preview = payload.items
+        .iter()
+        .zip(lines)
+        .map(|(item, line)| {
+            let left = remaining.entry((item.product_id, item.variant_id)).or_insert(line.stock);
+            if line.track_stock {
+                *left = left.saturating_sub(item.quantity);
+            }
+            StockPreviewLine {
+                product_id: item.product_id,
+                variant_id: item.variant_id,
+                current_stock: line.stock,
+                after_stock: *left,
+                sufficient: *left >= 0,
+            }
+        })
+        .collect();
+
+    Ok(Json(Collection::complete(preview)))
+}
+
+const MAX_BULK_ORDERS: usize = 100;
+
+const MAX_ORDER_ADJUSTMENTS: usize = 20;
+const MAX_ADJUSTMENT_LABEL_LEN: usize = 100;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct BulkOrderRequest {
+    orders: Vec<CreateOrder>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct BulkOrderResult {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order_id: Option<String>,
+    // machine-readable code and (localized) message, as in the REST error bodies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// orders are placed one after another, each in its own transaction, so a failing order only
+// affects its own entry; running them sequentially also keeps a large batch from holding more
+// than one write transaction (and pool connection) at a time. Every order counts against
+// CUSTOMER_ORDER_LIMIT like a POST /orders would, so once the limit is reached the rest of the
+// batch is rejected with too_many_orders
+async fn create_orders_bulk(auth: MaybeAuth, ConnectInfo(peer): ConnectInfo<SocketAddr>, State(state): State<Arc<AppState>>, Json(payload): Json<BulkOrderRequest>) -> Result<Json<Vec<BulkOrderResult>>, AppError> {
+    if payload.orders.len() > MAX_BULK_ORDERS {
+        return Err(AppError::BadRequest(format!("at most {} orders per request", MAX_BULK_ORDERS)));
+    }
+
+    let client_ip = peer.ip().to_string();
+    let mut results = Vec::with_capacity(payload.orders.len());
+    for (index, order) in payload.orders.iter().enumerate() {
+        let placed = match check_order_rate_limit(&state, auth.sub(), &client_ip).await {
+            Ok(()) => place_order(&state, order, auth.sub(), Some(&client_ip)).await,
+            Err(e) => Err(e),
+        };
+        let result = match placed {
+            Ok(placed) => BulkOrderResult { index, order_id: Some(placed.id), code: None, error: None },
+            Err(e) => {
+                // database errors are only logged; the entry gets the generic message
+                if !matches!(e, AppError::BadRequest(_)) {
+                    warn!(index, "bulk order failed: {}", e);
+                }
+                BulkOrderResult { index, order_id: None, code: Some(e.code()), error: Some(e.message()) }
+            }
+        };
+        results.push(result);
+    }
+
+    Ok(Json(results))
+}
+
+// tax is charged on top of the (tax-exclusive) line prices and rounded once for the whole order,
+// half up to the nearest cent, so per-line rounding errors cannot accumulate
+fn order_tax_cents(subtotal_cents: Money, tax_rate_bps: i64) -> Option<Money> {
+    let scaled = subtotal_cents.cents().checked_mul(tax_rate_bps)?.checked_add(5_000)?;
+    Some(Money::from_cents(scaled / 10_000))
+}
+
+// prices are only bounded below, so a large enough order could overflow i64 cents
+fn order_total_out_of_range() -> AppError {
+    AppError::BadRequest("order total is out of range".into())
+}
+
+// appends to the order's timeline; callers pass their own transaction so the event commits
+// (or rolls back) together with the change it describes
+async fn record_order_event(tx: &mut Transaction<'_, sqlx::Sqlite>, order_id: &str, event_type: &str, detail: Option<&str>) -> Result<(), AppError> {
+    let now = Utc::now().to_rfc3339();
+    sqlx::query!("INSERT INTO order_events (order_id, event_type, detail, created_at) VALUES (?, ?, ?, ?)", order_id, event_type, detail, now)
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+// starts a write transaction that holds SQLite's write lock from the start, like BEGIN IMMEDIATE.
+// A deferred BEGIN only asks for the lock at the first write, and if another connection
+// committed since this transaction's first read that upgrade fails with SQLITE_BUSY straight
+// away instead of waiting on busy_timeout. sqlx 0.7 always issues a plain BEGIN, so a write
+// statement that matches no rows takes the lock right after it. Transactions stay serializable
+// either way (SQLite has no weaker level); this only moves lock waits to the start
+async fn begin_write(pool: &SqlitePool) -> Result<Transaction<'static, sqlx::Sqlite>, AppError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE products SET id = id WHERE 0").execute(tx.as_mut()).await?;
+    Ok(tx)
+}
+
+// runs `body` in a write transaction, committing when it returns Ok and rolling back when it returns
+// Err, so a handler can't forget the commit or leave a half-applied change behind. `body` is
+// written as `|tx| Box::pin(async move { ... })`; tying the transaction to 'a lets the
+// future borrow from the calling handler
+async fn with_tx<'a, T, F>(pool: &SqlitePool, body: F) -> Result<T, AppError>
+where
+    F: for<'t> FnOnce(&'t mut Transaction<'a, sqlx::Sqlite>) -> BoxFuture<'t, Result<T, AppError>>,
+{
+    let mut tx: Transaction<'a, sqlx::Sqlite> = begin_write(pool).await?;
+    match body(&mut tx).await {
+        Ok(value) => {
+            tx.commit().await?;
+            Ok(value)
+        }
+        Err(e) => {
+            if let Err(rollback_error) = tx.rollback().await {
+                error!("transaction rollback failed: {}", rollback_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+// journals a change to a product's cached stock; like the order timeline it shares the
+// caller's transaction so the entry and the stock update commit together. For the reasons in
+// `REPLAYABLE_STOCK_REASONS` the ref_id names one operation, and a unique index rejects a second
+// entry for the same product, so a replayed order or adjustment fails instead of counting twice
+async fn record_stock_change(tx: &mut Transaction<'_, sqlx::Sqlite>, product_id: i64, delta: i64, reason: &str, ref_id: Option<&str>) -> Result<(), AppError> {
+    if delta == 0 {
+        return Ok(());
+    }
+    let now = Utc::now().to_rfc3339();
+    sqlx::query!("INSERT INTO stock_ledger (product_id, delta, reason, ref_id, created_at) VALUES (?, ?, ?, ?, ?)", product_id, delta, reason, ref_id, now)
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+// applies a change to a variant's stock and records it in variant_stock_history, in the caller's
+// transaction. Variants have their own history rather than stock_ledger entries: the ledger is
+// what products.stock is reconciled against, and variant stock is no part of that. ref_id is
+// unique per variant and reason here too. A variant deleted in the meantime is skipped
+async fn change_variant_stock(tx: &mut Transaction<'_, sqlx::Sqlite>, variant_id: i64, delta: i64, reason: &str, ref_id: Option<&str>) -> Result<(), AppError> {
+    let Some(row) = sqlx::query("UPDATE product_variants SET stock = stock + ? WHERE id = ? RETURNING stock")
+        .bind(delta)
+        .bind(variant_id)
+        .fetch_optional(tx.as_mut())
+        .await?
+    else {
+        return Ok(());
+    };
+    let new_stock: i64 = row.get("stock");
+    sqlx::query("INSERT INTO variant_stock_history (variant_id, old_stock, new_stock, reason, ref_id, changed_at) VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(variant_id)
+        .bind(new_stock - delta)
+        .bind(new_stock)
+        .bind(reason)
+        .bind(ref_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+// catalog changes a webhook subscription can listen for
+const PRODUCT_EVENTS: [&str; 3] = ["product.created", "product.updated", "product.deleted"];
+
+// queues a product event for every webhook subscription listening for it, in the caller's
+// transaction, carrying the product as that transaction left it. Stock movements from orders and
+// stock adjustments are not catalog changes and send nothing
+async fn record_product_event(tx: &mut Transaction<'_, sqlx::Sqlite>, event_type: &str, product_id: i64) -> Result<(), AppError> {
+    let listening: bool = sqlx::query("SELECT EXISTS (SELECT 1 FROM webhook_subscriptions s, json_each(s.events) e WHERE e.value = ?) AS listening")
+        .bind(event_type)
+        .fetch_one(tx.as_mut())
+        .await?
+        .get("listening");
+    if !listening {
+        return Ok(());
+    }
+    let row = sqlx::query(&format!("SELECT {}, deleted_at IS NOT NULL AS is_deleted FROM products WHERE id = ?", PRODUCT_COLUMNS))
+        .bind(product_id)
+        .fetch_one(tx.as_mut())
+        .await?;
+    let mut product = Product::from_row(&row)?.with_status();
+    product.deleted = Some(row.get("is_deleted"));
+    webhooks::enqueue_subscribers(tx, event_type, &json!({"product": product})).await?;
+    Ok(())
+}
+
+// allowed order status transitions
+fn can_transition(from: &str, to: &str) -> bool {
+    matches!(
+        (from, to),
+        ("pending", "paid") | ("pending", "cancelled") | ("paid", "shipped") | ("paid", "cancelled") | ("shipped", "delivered")
+    )
+}
+
+async fn update_order_status(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<UpdateOrderStatus>) -> Result<Json<OrderStatusEntry>, AppError> {
+    auth.require_admin()?;
+    let mut tx = begin_write(&state.pool).await?;
+
+    let current: String = sqlx::query("SELECT status FROM orders WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(tx.as_mut())
+        .await?
+        .ok_or(AppError::NotFound)?
+        .get("status");
+
+    if !can_transition(&current, &payload.status) {
+        return Err(AppError::BadRequest(format!("cannot change order status from {} to {}", current, payload.status)));
+    }
+
+    sqlx::query("UPDATE orders SET status = ? WHERE id = ?")
+        .bind(&payload.status)
+        .bind(&id)
+        .execute(tx.as_mut())
+        .await?;
+    record_order_event(&mut tx, &id, "status_changed", Some(&format!("{} -> {}", current, payload.status))).await?;
+    state.enqueue_event(&mut tx, "order.status_changed", json!({"order_id": id, "from": current, "to": payload.status})).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(OrderStatusEntry { id, status: Some(payload.status) }))
+}
+
+const MAX_REFUND_REASON_LEN: usize = 500;
+
+// orders can be refunded in several steps; the refunds together never exceed the order total
+async fn refund_order(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CreateRefund>) -> Result<(StatusCode, Json<RefundResponse>), AppError> {
+    auth.require_admin()?;
+    if payload.amount_cents <= 0 {
+        return Err(AppError::BadRequest("amount_cents must be > 0".into()));
+    }
+    let reason = payload.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    if reason.is_some_and(|r| r.chars().count() > MAX_REFUND_REASON_LEN) {
+        return Err(AppError::BadRequest(format!("reason must be at most {} characters", MAX_REFUND_REASON_LEN)));
+    }
+    let state: &AppState = &state;
+
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        let order = sqlx::query("SELECT status, total_cents FROM orders WHERE id = ?")
+            .bind(&id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .ok_or(AppError::NotFound)?;
+        let status: String = order.get("status");
+        if !matches!(status.as_str(), "paid" | "shipped" | "delivered") {
+            return Err(AppError::BadRequest(format!("cannot refund a {} order", status)));
+        }
+        let total_cents: i64 = order.get("total_cents");
+
+        // the limit is checked by the INSERT itself, so two concurrent refunds can't both pass a
+        // check made against the same stale sum
+        let inserted = sqlx::query(
+            "INSERT INTO refunds (order_id, amount_cents, reason, created_by, created_at) \
+             SELECT ?1, ?2, ?3, ?4, ?5 WHERE (SELECT COALESCE(SUM(amount_cents), 0) FROM refunds WHERE order_id = ?1) + ?2 <= ?6 \
+             RETURNING id"
+        )
+        .bind(&id)
+        .bind(payload.amount_cents)
+        .bind(reason)
+        .bind(auth.sub())
+        .bind(Utc::now().to_rfc3339())
+        .bind(total_cents)
+        .fetch_optional(tx.as_mut())
+        .await?;
+
+        let refunded_cents: i64 = sqlx::query("SELECT COALESCE(SUM(amount_cents), 0) AS refunded_cents FROM refunds WHERE order_id = ?")
+            .bind(&id)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("refunded_cents");
+        let refundable_cents = total_cents - refunded_cents;
+        let Some(inserted) = inserted else {
+            return Err(AppError::BadRequest(format!("refund exceeds the refundable amount of {} cents", refundable_cents)));
+        };
+
+        record_order_event(tx, &id, "refunded", Some(&format!("amount_cents={}", payload.amount_cents))).await?;
+        state.enqueue_event(tx, "order.refunded", json!({"order_id": id, "amount_cents": payload.amount_cents, "refunded_cents": refunded_cents})).await?;
+
+        Ok((StatusCode::CREATED, Json(RefundResponse {
+            refund_id: inserted.get("id"),
+            order_id: id,
+            amount_cents: payload.amount_cents,
+            refunded_cents,
+            refundable_cents,
+        })))
+    })).await
+}
+
+async fn order_timeline(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<OrderEvent>>, AppError> {
+    order_access(state.read_pool(), &auth, &id).await?;
+
+    let rows = sqlx::query("SELECT event_type, detail, created_at FROM order_events WHERE order_id = ? ORDER BY created_at, id")
+        .bind(&id)
+        .fetch_all(state.read_pool())
+        .await?;
+
+    let events = rows
+        .into_iter()
+        .map(|r| OrderEvent {
+            event_type: r.get("event_type"),
+            detail: r.get("detail"),
+            created_at: r.get("created_at"),
+        })
+        .collect();
+
+    Ok(Json(Collection::complete(events)))
+}
+
+// attachment types accepted on upload, compared against the part's declared Content-Type
+const ATTACHMENT_CONTENT_TYPES: &[&str] = &["application/pdf", "image/png", "image/jpeg", "text/plain", "text/csv"];
+const MAX_ATTACHMENTS_PER_ORDER: i64 = 20;
+const MAX_ATTACHMENT_FILENAME_LEN: usize = 255;
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderAttachment {
+    id: i64,
+    order_id: String,
+    filename: String,
+    content_type: String,
+    size_bytes: i64,
+    created_by: Option<String>,
+    created_at: String,
+}
+
+// keeps the last path segment of a client-supplied filename and drops anything that could break
+// out of the quoted Content-Disposition value on download
+fn sanitize_filename(raw: &str) -> String {
+    let name = raw.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control() && *c != '"').take(MAX_ATTACHMENT_FILENAME_LEN).collect();
+    let name = name.trim();
+    if name.is_empty() { "attachment".to_string() } else { name.to_string() }
+}
+
+// attachments are limited to the order's customer and admins, like its notes and timeline. The
+// upload is a multipart form with one `file` part, read chunk by chunk so an oversized file is
+// rejected without being buffered whole
+async fn upload_order_attachment(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, multipart: Result<axum::extract::Multipart, axum::extract::multipart::MultipartRejection>) -> Result<(StatusCode, [(header::HeaderName, String); 1], Json<OrderAttachment>), AppError> {
+    order_access(&state.pool, &auth, &id).await?;
+    let mut multipart = multipart.map_err(|e| AppError::BadRequest(e.body_text()))?;
+    let read_error = |e: axum::extract::multipart::MultipartError| {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE { AppError::PayloadTooLarge(state.attachment_max_bytes) } else { AppError::BadRequest(e.body_text()) }
+    };
+
+    let mut upload = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(read_error)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let content_type = field.content_type().unwrap_or("application/octet-stream").split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if !ATTACHMENT_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(AppError::BadRequest(format!("content type {} is not allowed (allowed: {})", content_type, ATTACHMENT_CONTENT_TYPES.join(", "))));
+        }
+        let filename = sanitize_filename(field.file_name().unwrap_or_default());
+        let mut data = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(read_error)? {
+            if data.len() + chunk.len() > state.attachment_max_bytes {
+                return Err(AppError::PayloadTooLarge(state.attachment_max_bytes));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        upload = Some((filename, content_type, data));
+        break;
+    }
+    let Some((filename, content_type, data)) = upload else {
+        return Err(AppError::BadRequest("multipart form must contain a file part".into()));
+    };
+    if data.is_empty() {
+        return Err(AppError::BadRequest("file must not be empty".into()));
+    }
+
+    let state: &AppState = &state;
+    let attachment = with_tx(&state.pool, |tx| Box::pin(async move {
+        let attached: Option<i64> = sqlx::query("SELECT (SELECT COUNT(*) FROM order_attachments WHERE order_id = o.id) AS attached FROM orders o WHERE o.id = ?")
+            .bind(&id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .map(|r| r.get("attached"));
+        match attached {
+            None => return Err(AppError::NotFound),
+            Some(n) if n >= MAX_ATTACHMENTS_PER_ORDER => {
+                return Err(AppError::BadRequest(format!("an order can have at most {} attachments", MAX_ATTACHMENTS_PER_ORDER)));
+            }
+            Some(_) => {}
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let size_bytes = data.len() as i64;
+        let attachment_id: i64 = sqlx::query("INSERT INTO order_attachments (order_id, filename, content_type, size_bytes, data, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING id")
+            .bind(&id)
+            .bind(&filename)
+            .bind(&content_type)
+            .bind(size_bytes)
+            .bind(&data)
+            .bind(auth.sub())
+            .bind(&now)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("id");
+        record_order_event(tx, &id, "attachment_added", Some(&filename)).await?;
+
+        Ok(OrderAttachment { id: attachment_id, order_id: id, filename, content_type, size_bytes, created_by: auth.sub().map(str::to_string), created_at: now })
+    })).await?;
+
+    let location = state.location(&format!("orders/{}/attachments", attachment.order_id), attachment.id);
+    Ok((StatusCode::CREATED, location, Json(attachment)))
+}
+
+async fn list_order_attachments(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<OrderAttachment>>, AppError> {
+    order_access(state.read_pool(), &auth, &id).await?;
+
+    let rows = sqlx::query("SELECT id, order_id, filename, content_type, size_bytes, created_by, created_at FROM order_attachments WHERE order_id = ? ORDER BY id")
+        .bind(&id)
+        .fetch_all(state.read_pool())
+        .await?;
+    let attachments = rows
+        .into_iter()
+        .map(|r| OrderAttachment {
+            id: r.get("id"),
+            order_id: r.get("order_id"),
+            filename: r.get("filename"),
+            content_type: r.get("content_type"),
+            size_bytes: r.get("size_bytes"),
+            created_by: r.get("created_by"),
+            created_at: r.get("created_at"),
+        })
+        .collect();
+    Ok(Json(Collection::complete(attachments)))
+}
+
+// always served as a download (never rendered inline) and with sniffing off, so a mislabelled
+// upload can't be run by the browser in this origin
+async fn download_order_attachment(Path((id, attachment_id)): Path<(String, i64)>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    order_access(state.read_pool(), &auth, &id).await?;
+    let row = sqlx::query("SELECT filename, content_type, data FROM order_attachments WHERE id = ? AND order_id = ?")
+        .bind(attachment_id)
+        .bind(&id)
+        .fetch_optional(state.read_pool())
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let filename: String = row.get("filename");
+    let content_type: String = row.get("content_type");
+    let data: Vec<u8> = row.get("data");
+
+    let mut res = data.into_response();
+    let headers = res.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap_or(HeaderValue::from_static("attachment"));
+    headers.insert(header::CONTENT_DISPOSITION, disposition);
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(res)
+}
+
+const MAX_NOTE_LEN: usize = 2000;
+const MAX_NOTES_PER_ORDER: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CreateOrderNote {
+    body: String,
+    // staff-only; defaults to a note the customer can see
+    #[serde(default)]
+    internal: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderNote {
+    id: i64,
+    order_id: String,
+    author: Option<String>,
+    body: String,
+    internal: bool,
+    created_at: String,
+}
+
+// who may see an order's history and read or write its notes: admins, and the customer who
+// placed it. Guest orders are admin-only. Ok(true) means staff, who also see internal notes
+async fn order_access(pool: &SqlitePool, auth: &MaybeAuth, order_id: &str) -> Result<bool, AppError> {
+    let customer_id: Option<String> = sqlx::query("SELECT customer_id FROM orders WHERE id = ?")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::NotFound)?
+        .get("customer_id");
+    match auth.sub() {
+        None => Err(AppError::Unauthorized),
+        Some(_) if auth.is_admin() => Ok(true),
+        Some(sub) if Some(sub) == customer_id.as_deref() => Ok(false),
+        Some(_) => Err(AppError::Forbidden),
+    }
+}
+
+async fn create_order_note(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CreateOrderNote>) -> Result<(StatusCode, Json<OrderNote>), AppError> {
+    let body = payload.body.trim();
+    let body_len = body.chars().count();
+    if body_len == 0 || body_len > MAX_NOTE_LEN {
+        return Err(AppError::BadRequest(format!("body must be 1-{} characters", MAX_NOTE_LEN)));
+    }
+    let staff = order_access(&state.pool, &auth, &id).await?;
+    if payload.internal && !staff {
+        return Err(AppError::Forbidden);
+    }
+
+    let state: &AppState = &state;
+    let note = with_tx(&state.pool, |tx| Box::pin(async move {
+        let count: i64 = sqlx::query("SELECT COUNT(*) AS n FROM order_notes WHERE order_id = ?")
+            .bind(&id)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("n");
+        if count >= MAX_NOTES_PER_ORDER {
+            return Err(AppError::BadRequest(format!("an order can have at most {} notes", MAX_NOTES_PER_ORDER)));
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let note_id: i64 = sqlx::query("INSERT INTO order_notes (order_id, author, body, internal, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id")
+            .bind(&id)
+            .bind(auth.sub())
+            .bind(body)
+            .bind(payload.internal)
+            .bind(&now)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("id");
+        Ok(OrderNote { id: note_id, order_id: id, author: auth.sub().map(str::to_string), body: body.to_string(), internal: payload.internal, created_at: now })
+    })).await?;
+
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+// oldest first, like the order timeline
+async fn list_order_notes(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<OrderNote>>, AppError> {
+    let staff = order_access(state.read_pool(), &auth, &id).await?;
+    let rows = sqlx::query("SELECT id, order_id, author, body, internal, created_at FROM order_notes WHERE order_id = ? AND (? OR NOT internal) ORDER BY id")
+        .bind(&id)
+        .bind(staff)
+        .fetch_all(state.read_pool())
+        .await?;
+
+    let notes = rows
+        .into_iter()
+        .map(|r| OrderNote {
+            id: r.get("id"),
+            order_id: r.get("order_id"),
+            author: r.get("author"),
+            body: r.get("body"),
+            internal: r.get("internal"),
+            created_at: r.get("created_at"),
+        })
+        .collect();
+    Ok(Json(Collection::complete(notes)))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderItemDetail {
+    product_id: i64,
+    variant_id: Option<i64>,
+    quantity: i32,
+    // price per unit at the time of the order, after any quantity tier discount
+    unit_price_cents: i64,
+}
+
+// response of GET /orders/:id; the money fields match OrderResponse, with
+// total_cents = subtotal_cents + adjustments_cents + tax_cents
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderDetail {
+    id: String,
+    order_number: Option<String>,
+    status: String,
+    subtotal_cents: i64,
+    adjustments_cents: i64,
+    tax_cents: i64,
+    total_cents: i64,
+    refunded_cents: i64,
+    refundable_cents: i64,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    items: Vec<OrderItemDetail>,
+    adjustments: Vec<OrderAdjustment>,
+    // only shown to admins and the customer who placed the order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping_address: Option<OrderAddress>,
+}
+
+const ORDER_NUMBER_PREFIX: &str = "ORD-";
+
+fn format_order_number(n: i64) -> String {
+    format!("{}{:06}", ORDER_NUMBER_PREFIX, n)
+}
+
+// "ORD-000123" (prefix case-insensitive, zero padding optional) -> 123
+fn parse_order_number(s: &str) -> Option<i64> {
+    let digits = s.get(ORDER_NUMBER_PREFIX.len()..).filter(|_| s[..ORDER_NUMBER_PREFIX.len()].eq_ignore_ascii_case(ORDER_NUMBER_PREFIX))?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// :id may be the order's UUID or its human-friendly order number
+async fn get_order(Path(id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<OrderDetail>, AppError> {
+    let order_number = parse_order_number(&id);
+    let row = match order_number {
+        Some(number) => sqlx::query("SELECT id, order_number, status, customer_id, subtotal_cents, tax_cents, total_cents, created_at FROM orders WHERE order_number = ?")
+            .bind(number)
+            .fetch_optional(state.read_pool())
+            .await?,
+        None => sqlx::query("SELECT id, order_number, status, customer_id, subtotal_cents, tax_cents, total_cents, created_at FROM orders WHERE id = ?")
+            .bind(&id)
+            .fetch_optional(state.read_pool())
+            .await?,
+    };
+
+    if let Some(r) = row {
+        // order numbers are sequential and easy to guess, so only the customer who placed the
+        // order and admins can look one up; the same goes for a customer's order by UUID. An
+        // anonymous order is only reachable by its UUID. Anyone else gets a 404, not a 403, so
+        // lookups can't be used to probe which orders exist
+        let customer_id: Option<String> = r.get("customer_id");
+        let owner_or_staff = auth.is_admin() || (auth.sub().is_some() && auth.sub() == customer_id.as_deref());
+        if !owner_or_staff && (order_number.is_some() || customer_id.is_some()) {
+            return Err(AppError::NotFound);
+        }
+
+        let id: String = r.get("id");
+        let items = sqlx::query("SELECT product_id, variant_id, quantity, unit_price_cents FROM order_items WHERE order_id = ?")
+            .bind(&id)
+            .fetch_all(state.read_pool())
+            .await?;
+
+        let items: Vec<OrderItemDetail> = items.into_iter().map(|it| OrderItemDetail {
+            product_id: it.get("product_id"),
+            variant_id: it.get("variant_id"),
+            quantity: it.get("quantity"),
+            unit_price_cents: it.get("unit_price_cents"),
+        }).collect();
+
+        let adjustments: Vec<OrderAdjustment> = sqlx::query("SELECT label, amount_cents FROM order_adjustments WHERE order_id = ? ORDER BY id")
+            .bind(&id)
+            .fetch_all(state.read_pool())
+            .await?
+            .into_iter()
+            .map(|a| OrderAdjustment { label: a.get("label"), amount_cents: a.get("amount_cents") })
+            .collect();
+
+        let refunded_cents: i64 = sqlx::query("SELECT COALESCE(SUM(amount_cents), 0) AS refunded_cents FROM refunds WHERE order_id = ?")
+            .bind(&id)
+            .fetch_one(state.read_pool())
+            .await?
+            .get("refunded_cents");
+        let total_cents: i64 = r.get("total_cents");
+        let status: String = r.get("status");
+        let created_at: String = r.get("created_at");
+
+        let shipping_address = if owner_or_staff {
+            sqlx::query("SELECT address_id, line1, line2, city, region, postal_code, country FROM order_addresses WHERE order_id = ? AND kind = 'shipping'")
+                .bind(&id)
+                .fetch_optional(state.read_pool())
+                .await?
+                .map(|a| OrderAddress {
+                    address_id: a.get("address_id"),
+                    line1: a.get("line1"),
+                    line2: a.get("line2"),
+                    city: a.get("city"),
+                    region: a.get("region"),
+                    postal_code: a.get("postal_code"),
+                    country: a.get("country"),
+                })
+        } else {
+            None
+        };
+
+        Ok(Json(OrderDetail {
+            id,
+            order_number: r.get::<Option<i64>, _>("order_number").map(format_order_number),
+            expires_at: state.order_expires_at(&status, &created_at),
+            status,
+            subtotal_cents: r.get("subtotal_cents"),
+            adjustments_cents: adjustments.iter().map(|a| a.amount_cents).sum(),
+            tax_cents: r.get("tax_cents"),
+            total_cents,
+            refunded_cents,
+            refundable_cents: total_cents - refunded_cents,
+            created_at,
+            items,
+            adjustments,
+            shipping_address,
+        }))
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+const MAX_STATUS_BATCH: usize = 200;
+
+// customers only get statuses for their own orders; anyone else's come back like unknown ids,
+// so the batch can't be used to probe which ids exist
+async fn order_status_batch(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<OrderStatusBatchRequest>) -> Result<Json<Vec<OrderStatusEntry>>, AppError> {
+    let customer_id = auth.sub().ok_or(AppError::Unauthorized)?;
+    if payload.ids.len() > MAX_STATUS_BATCH {
+        return Err(AppError::BadRequest(format!("at most {} ids per request", MAX_STATUS_BATCH)));
+    }
+    if payload.ids.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let placeholders = vec!["?"; payload.ids.len()].join(", ");
+    let sql = format!("SELECT id, status FROM orders WHERE id IN ({}) AND (? OR customer_id = ?)", placeholders);
+    let mut query = sqlx::query(&sql);
+    for id in &payload.ids {
+        query = query.bind(id);
+    }
+    let rows = query.bind(auth.is_admin()).bind(customer_id).fetch_all(state.read_pool()).await?;
+
+    let found: std::collections::HashMap<String, String> = rows
+        .into_iter()
+        .map(|r| (r.get("id"), r.get("status")))
+        .collect();
+
+    // keep the caller's ordering; unknown ids come back flagged with a null status
+    let entries = payload.ids
+        .into_iter()
+        .map(|id| {
+            let status = found.get(&id).cloned();
+            OrderStatusEntry { id, status }
+        })
+        .collect();
+
+    Ok(Json(entries))
+}
+
+const ADDRESS_TYPES: [&str; 2] = ["shipping", "billing"];
+const MAX_ADDRESS_FIELD_LEN: usize = 200;
+const MAX_ADDRESSES_PER_CUSTOMER: i64 = 50;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct AddressRequest {
+    #[serde(rename = "type")]
+    kind: String,
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    region: Option<String>,
+    postal_code: Option<String>,
+    // ISO 3166-1 alpha-2, e.g. "DE"
+    country: String,
+    // making an address the default takes the flag from the customer's other one of that type
+    #[serde(default)]
+    is_default: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CustomerAddress {
+    id: i64,
+    customer_id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    region: Option<String>,
+    postal_code: Option<String>,
+    country: String,
+    is_default: bool,
+    created_at: String,
+    updated_at: String,
+}
+
+// an order's copy of the address it ships to; address_id is the saved address it came from,
+// which may since have been changed or deleted
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct OrderAddress {
+    address_id: Option<i64>,
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    region: Option<String>,
+    postal_code: Option<String>,
+    country: String,
+}
+
+const CUSTOMER_ADDRESS_COLUMNS: &str = "id, customer_id, type, line1, line2, city, region, postal_code, country, is_default, created_at, updated_at";
+
+fn customer_address_from_row(r: &SqliteRow) -> CustomerAddress {
+    CustomerAddress {
+        id: r.get("id"),
+        customer_id: r.get("customer_id"),
+        kind: r.get("type"),
+        line1: r.get("line1"),
+        line2: r.get("line2"),
+        city: r.get("city"),
+        region: r.get("region"),
+        postal_code: r.get("postal_code"),
+        country: r.get("country"),
+        is_default: r.get("is_default"),
+        created_at: r.get("created_at"),
+        updated_at: r.get("updated_at"),
+    }
+}
+
+// trims every field and turns blank optional ones into None
+fn validate_address(mut payload: AddressRequest) -> Result<AddressRequest, AppError> {
+    if !ADDRESS_TYPES.contains(&payload.kind.as_str()) {
+        return Err(AppError::BadRequest(format!("type must be one of {}", ADDRESS_TYPES.join(", "))));
+    }
+    for (field, value) in [("line1", &mut payload.line1), ("city", &mut payload.city)] {
+        *value = value.trim().to_string();
+        if value.is_empty() {
+            return Err(AppError::BadRequest(format!("{} must not be empty", field)));
+        }
+    }
+    for value in [&mut payload.line2, &mut payload.region, &mut payload.postal_code] {
+        *value = value.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+    }
+    let fields = [Some(&payload.line1), payload.line2.as_ref(), Some(&payload.city), payload.region.as_ref(), payload.postal_code.as_ref()];
+    if fields.into_iter().flatten().any(|v| v.chars().count() > MAX_ADDRESS_FIELD_LEN) {
+        return Err(AppError::BadRequest(format!("address fields must be at most {} characters", MAX_ADDRESS_FIELD_LEN)));
+    }
+    payload.country = payload.country.trim().to_ascii_uppercase();
+    if payload.country.len() != 2 || !payload.country.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(AppError::BadRequest("country must be a two-letter ISO 3166 code".into()));
+    }
+    Ok(payload)
+}
+
+// clears the default flag on the customer's other addresses of the same type, so the one being
+// written can take it without tripping idx_customer_addresses_default
+async fn clear_default_address(tx: &mut Transaction<'_, sqlx::Sqlite>, customer_id: &str, kind: &str, except: Option<i64>) -> Result<(), AppError> {
+    sqlx::query("UPDATE customer_addresses SET is_default = 0 WHERE customer_id = ? AND type = ? AND is_default AND id IS NOT ?")
+        .bind(customer_id)
+        .bind(kind)
+        .bind(except)
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+async fn list_customer_addresses(Path(customer_id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<CustomerAddress>>, AppError> {
+    auth.require_customer(&customer_id)?;
+    let rows = sqlx::query(&format!("SELECT {} FROM customer_addresses WHERE customer_id = ? ORDER BY id", CUSTOMER_ADDRESS_COLUMNS))
+        .bind(&customer_id)
+        .fetch_all(state.read_pool())
+        .await?;
+    Ok(Json(Collection::complete(rows.iter().map(customer_address_from_row).collect())))
+}
+
+async fn get_customer_address(Path((customer_id, id)): Path<(String, i64)>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<CustomerAddress>, AppError> {
+    auth.require_customer(&customer_id)?;
+    let row = sqlx::query(&format!("SELECT {} FROM customer_addresses WHERE id = ? AND customer_id = ?", CUSTOMER_ADDRESS_COLUMNS))
+        .bind(id)
+        .bind(&customer_id)
+        .fetch_optional(state.read_pool())
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(customer_address_from_row(&row)))
+}
+
+async fn create_customer_address(Path(customer_id): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<AddressRequest>) -> Result<(StatusCode, [(header::HeaderName, String); 1], Json<CustomerAddress>), AppError> {
+    auth.require_customer(&customer_id)?;
+    let address = validate_address(payload)?;
+    let customer_id = customer_id.as_str();
+    let address = &address;
+    let row = with_tx(&state.pool, |tx| Box::pin(async move {
+        let existing: i64 = sqlx::query("SELECT COUNT(*) AS n FROM customer_addresses WHERE customer_id = ?")
+            .bind(customer_id)
+            .fetch_one(tx.as_mut())
+            .await?
+            .get("n");
+        if existing >= MAX_ADDRESSES_PER_CUSTOMER {
+            return Err(AppError::BadRequest(format!("at most {} addresses per customer", MAX_ADDRESSES_PER_CUSTOMER)));
+        }
+        if address.is_default {
+            clear_default_address(tx, customer_id, &address.kind, None).await?;
+        }
+        let now = Utc::now().to_rfc3339();
+        let sql = format!(
+            "INSERT INTO customer_addresses (customer_id, type, line1, line2, city, region, postal_code, country, is_default, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+            CUSTOMER_ADDRESS_COLUMNS
+        );
+        Ok(sqlx::query(&sql)
+            .bind(customer_id)
+            .bind(&address.kind)
+            .bind(&address.line1)
+            .bind(&address.line2)
+            .bind(&address.city)
+            .bind(&address.region)
+            .bind(&address.postal_code)
+            .bind(&address.country)
+            .bind(address.is_default)
+            .bind(&now)
+            .bind(&now)
+            .fetch_one(tx.as_mut())
+            .await?)
+    })).await?;
+
+    let address = customer_address_from_row(&row);
+    Ok((StatusCode::CREATED, state.location(&format!("customers/{}/addresses", customer_id), address.id), Json(address)))
+}
+
+// replaces the whole address; orders already placed keep the copy they were placed with
+async fn replace_customer_address(Path((customer_id, id)): Path<(String, i64)>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<AddressRequest>) -> Result<Json<CustomerAddress>, AppError> {
+    auth.require_customer(&customer_id)?;
+    let address = validate_address(payload)?;
+    let customer_id = customer_id.as_str();
+    let address = &address;
+    let row = with_tx(&state.pool, |tx| Box::pin(async move {
+        if address.is_default {
+            clear_default_address(tx, customer_id, &address.kind, Some(id)).await?;
+        }
+        let sql = format!(
+            "UPDATE customer_addresses SET type = ?, line1 = ?, line2 = ?, city = ?, region = ?, postal_code = ?, country = ?, is_default = ?, updated_at = ? \
+             WHERE id = ? AND customer_id = ? RETURNING {}",
+            CUSTOMER_ADDRESS_COLUMNS
+        );
+        sqlx::query(&sql)
+            .bind(&address.kind)
+            .bind(&address.line1)
+            .bind(&address.line2)
+            .bind(&address.city)
+            .bind(&address.region)
+            .bind(&address.postal_code)
+            .bind(&address.country)
+            .bind(address.is_default)
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .bind(customer_id)
+            .fetch_optional(tx.as_mut())
+            .await?
+            .ok_or(AppError::NotFound)
+    })).await?;
+    Ok(Json(customer_address_from_row(&row)))
+}
+
+async fn delete_customer_address(Path((customer_id, id)): Path<(String, i64)>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
+    auth.require_customer(&customer_id)?;
+    let res = sqlx::query("DELETE FROM customer_addresses WHERE id = ? AND customer_id = ?")
+        .bind(id)
+        .bind(&customer_id)
+        .execute(&state.pool)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn category_from_row(r: &sqlx::sqlite::SqliteRow) -> Category {
+    Category {
+        id: r.get("id"),
+        name: r.get("name"),
+        created_at: r.get("created_at"),
+        updated_at: r.get("updated_at"),
+    }
+}
+
+fn validate_category_name(state: &AppState, name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("name must not be empty".into()));
+    }
+    if name.chars().count() > state.max_name_len {
+        return Err(AppError::BadRequest(format!("name must be at most {} characters", state.max_name_len)));
+    }
+    Ok(name.to_string())
+}
+
+async fn list_categories(State(state): State<Arc<AppState>>) -> Result<Json<Collection<Category>>, AppError> {
+    let rows = sqlx::query("SELECT id, name, created_at, updated_at FROM categories ORDER BY name")
+        .fetch_all(state.read_pool())
+        .await?;
+    Ok(Json(Collection::complete(rows.iter().map(category_from_row).collect())))
+}
+
+// idempotent: creating a name that already exists (compared case-insensitively) returns the
+// existing category with 200 instead of failing, so taxonomy syncs can simply replay everything
+async fn create_category(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CategoryName>) -> Result<(StatusCode, Json<Category>), AppError> {
+    auth.require_admin()?;
+    let name = validate_category_name(&state, &payload.name)?;
+    let now = Utc::now().to_rfc3339();
+    let inserted = sqlx::query("INSERT INTO categories (name, created_at, updated_at) VALUES (?, ?, ?) ON CONFLICT(name) DO NOTHING RETURNING id, name, created_at, updated_at")
+        .bind(&name)
+        .bind(&now)
+        .bind(&now)
+        .fetch_optional(&state.pool)
+        .await?;
+    if let Some(row) = inserted {
+        return Ok((StatusCode::CREATED, Json(category_from_row(&row))));
+    }
+
+    let existing = sqlx::query("SELECT id, name, created_at, updated_at FROM categories WHERE name = ?")
+        .bind(&name)
+        .fetch_one(&state.pool)
+        .await?;
+    Ok((StatusCode::OK, Json(category_from_row(&existing))))
+}
+
+async fn rename_category(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CategoryName>) -> Result<Json<Category>, AppError> {
+    auth.require_admin()?;
+    let name = validate_category_name(&state, &payload.name)?;
+    let row = sqlx::query("UPDATE categories SET name = ?, updated_at = ? WHERE id = ? RETURNING id, name, created_at, updated_at")
+        .bind(&name)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(duplicate_name_error)?;
+    row.map(|r| Json(category_from_row(&r))).ok_or(AppError::NotFound)
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CategorySummary {
+    category_id: i64,
+    product_count: i64,
+    // stock and value only count products with track_stock, like /stats/inventory-value
+    total_stock: i64,
+    out_of_stock_count: i64,
+    inventory_value_cents: i64,
+}
+
+// inventory health of one category's live products; an empty category is all zeros. Categories
+// are flat, so there are no descendants to include
+async fn category_summary(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<CategorySummary>, AppError> {
+    auth.require_admin()?;
+    let sql = format!(
+        "SELECT EXISTS (SELECT 1 FROM categories WHERE id = ?1) AS found, COUNT(*) AS product_count, \
+         COALESCE(SUM(CASE WHEN track_stock THEN stock END), 0) AS total_stock, \
+         COALESCE(SUM(NOT {}), 0) AS out_of_stock_count, \
+         COALESCE(SUM(CASE WHEN track_stock THEN stock * price_cents END), 0) AS inventory_value_cents \
+         FROM products WHERE deleted_at IS NULL AND category_id = ?1",
+        IN_STOCK_SQL
+    );
+    let row = sqlx::query(&sql).bind(id).fetch_one(state.read_pool()).await?;
+    if !row.get::<bool, _>("found") {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(Json(CategorySummary {
+        category_id: id,
+        product_count: row.get("product_count"),
+        total_stock: row.get("total_stock"),
+        out_of_stock_count: row.get("out_of_stock_count"),
+        inventory_value_cents: row.get("inventory_value_cents"),
+    }))
+}
+
+async fn list_currency_rates(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<CurrencyRate>>, AppError> {
+    auth.require_admin()?;
+    let rows = sqlx::query("SELECT code, rate_to_base, minor_units FROM currency_rates ORDER BY code")
+        .fetch_all(&state.pool)
+        .await?;
+
+    let rates = rows
+        .into_iter()
+        .map(|r| CurrencyRate {
+            code: r.get("code"),
+            rate_to_base: r.get("rate_to_base"),
+            minor_units: r.get("minor_units"),
+        })
+        .collect();
+
+    Ok(Json(Collection::complete(rates)))
+}
+
+async fn upsert_currency_rate(Path(code): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<UpsertCurrencyRate>) -> Result<Json<CurrencyRate>, AppError> {
+    auth.require_admin()?;
+    let code = normalize_currency_code(&code)?;
+    if !(payload.rate_to_base.is_finite() && payload.rate_to_base > 0.0) {
+        return Err(AppError::BadRequest("rate_to_base must be > 0".into()));
+    }
+    let minor_units = payload.minor_units.unwrap_or(2);
+    if !(0..=4).contains(&minor_units) {
+        return Err(AppError::BadRequest("minor_units must be between 0 and 4".into()));
+    }
+
+    sqlx::query("INSERT INTO currency_rates (code, rate_to_base, minor_units) VALUES (?, ?, ?) ON CONFLICT(code) DO UPDATE SET rate_to_base = excluded.rate_to_base, minor_units = excluded.minor_units")
+        .bind(&code)
+        .bind(payload.rate_to_base)
+        .bind(minor_units)
+        .execute(&state.pool)
+        .await?;
+
+    Ok(Json(CurrencyRate { code, rate_to_base: payload.rate_to_base, minor_units }))
+}
+
+async fn delete_currency_rate(Path(code): Path<String>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
+    auth.require_admin()?;
+    let code = normalize_currency_code(&code)?;
+    let res = sqlx::query("DELETE FROM currency_rates WHERE code = ?")
+        .bind(&code)
+        .execute(&state.pool)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(AppError::NotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CreateWebhookSubscription {
+    url: String,
+    events: Vec<String>,
+    secret: Option<String>,
+}
+
+// omitted fields keep their value; an empty secret removes it
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct UpdateWebhookSubscription {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    secret: Option<String>,
+}
+
+// the secret is write-only; responses only say whether one is set
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct WebhookSubscription {
+    id: i64,
+    url: String,
+    events: Vec<String>,
+    has_secret: bool,
+    created_at: String,
+    updated_at: String,
+}
+
+const WEBHOOK_SUBSCRIPTION_COLUMNS: &str = "id, url, events, secret IS NOT NULL AS has_secret, created_at, updated_at";
+
+fn webhook_subscription_from_row(r: &SqliteRow) -> WebhookSubscription {
+    WebhookSubscription {
+        id: r.get("id"),
+        url: r.get("url"),
+        events: serde_json::from_str(r.get("events")).unwrap_or_default(),
+        has_secret: r.get("has_secret"),
+        created_at: r.get("created_at"),
+        updated_at: r.get("updated_at"),
+    }
+}
+
+fn validate_webhook_url(url: &str) -> Result<String, AppError> {
+    let url = url.trim();
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Ok(url.to_string()),
+        _ => Err(AppError::BadRequest("url must be an absolute http or https URL".into())),
+    }
+}
+
+// stored as a JSON array, without duplicates
+fn validate_webhook_events(events: &[String]) -> Result<String, AppError> {
+    if events.is_empty() {
+        return Err(AppError::BadRequest("events must not be empty".into()));
+    }
+    let mut unique: Vec<&str> = Vec::new();
+    for event in events {
+        if !PRODUCT_EVENTS.contains(&event.as_str()) {
+            return Err(AppError::BadRequest(format!("unknown event {:?}, expected one of {}", event, PRODUCT_EVENTS.join(", "))));
+        }
+        if !unique.contains(&event.as_str()) {
+            unique.push(event);
+        }
+    }
+    Ok(json!(unique).to_string())
+}
+
+async fn list_webhook_subscriptions(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<Collection<WebhookSubscription>>, AppError> {
+    auth.require_admin()?;
+    let rows = sqlx::query(&format!("SELECT {} FROM webhook_subscriptions ORDER BY id", WEBHOOK_SUBSCRIPTION_COLUMNS))
+        .fetch_all(&state.pool)
+        .await?;
+    Ok(Json(Collection::complete(rows.iter().map(webhook_subscription_from_row).collect())))
+}
+
+async fn get_webhook_subscription(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<WebhookSubscription>, AppError> {
+    auth.require_admin()?;
+    let row = sqlx::query(&format!("SELECT {} FROM webhook_subscriptions WHERE id = ?", WEBHOOK_SUBSCRIPTION_COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(webhook_subscription_from_row(&row)))
+}
+
+// product events are delivered through the outbox like order events, see `record_product_event`
+async fn create_webhook_subscription(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<CreateWebhookSubscription>) -> Result<(StatusCode, [(header::HeaderName, String); 1], Json<WebhookSubscription>), AppError> {
+    auth.require_admin()?;
+    let url = validate_webhook_url(&payload.url)?;
+    let events = validate_webhook_events(&payload.events)?;
+    let now = Utc::now().to_rfc3339();
+    let row = sqlx::query(&format!("INSERT INTO webhook_subscriptions (url, events, secret, created_at, updated_at) VALUES (?, ?, ?, ?, ?) RETURNING {}", WEBHOOK_SUBSCRIPTION_COLUMNS))
+        .bind(&url)
+        .bind(&events)
+        .bind(payload.secret.as_deref().filter(|s| !s.is_empty()))
+        .bind(&now)
+        .bind(&now)
+        .fetch_one(&state.pool)
+        .await?;
+
+    let subscription = webhook_subscription_from_row(&row);
+    Ok((StatusCode::CREATED, state.location("admin/webhook-subscriptions", subscription.id), Json(subscription)))
+}
+
+async fn update_webhook_subscription(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<UpdateWebhookSubscription>) -> Result<Json<WebhookSubscription>, AppError> {
+    auth.require_admin()?;
+    let url = payload.url.as_deref().map(validate_webhook_url).transpose()?;
+    let events = payload.events.as_deref().map(validate_webhook_events).transpose()?;
+    let row = sqlx::query(&format!(
+        "UPDATE webhook_subscriptions SET url = COALESCE(?, url), events = COALESCE(?, events), secret = CASE WHEN ? THEN NULLIF(?, '') ELSE secret END, updated_at = ? WHERE id = ? RETURNING {}",
+        WEBHOOK_SUBSCRIPTION_COLUMNS
+    ))
+        .bind(url)
+        .bind(events)
+        .bind(payload.secret.is_some())
+        .bind(payload.secret)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Json(webhook_subscription_from_row(&row)))
+}
+
+// events already queued for the subscription are dropped with it
+async fn delete_webhook_subscription(Path(id): Path<i64>, auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
+    auth.require_admin()?;
+    with_tx(&state.pool, |tx| Box::pin(async move {
+        let deleted = sqlx::query("DELETE FROM webhook_subscriptions WHERE id = ?")
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(AppError::NotFound);
+        }
+        sqlx::query("DELETE FROM outbox WHERE subscription_id = ? AND delivered = 0")
+            .bind(id)
+            .execute(tx.as_mut())
+            .await?;
+        Ok(())
+    })).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct VersionInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: Option<String>,
+}
+
+// GIT_SHA and BUILD_TIMESTAMP (unix seconds) are set by build.rs
+async fn get_version() -> Json<VersionInfo> {
+    let built_at = env!("BUILD_TIMESTAMP").parse::<i64>().ok().and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0)).map(|t| t.to_rfc3339());
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        built_at,
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct DiagnosticsSnapshot {
+    uptime_secs: u64,
+    requests_total: u64,
+    errors_by_variant: std::collections::BTreeMap<&'static str, u64>,
+    slow_queries: u64,
+    product_read_coalescing: CoalescingStats,
+    pool: PoolStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replica_pool: Option<PoolStats>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CoalescingStats {
+    coalesced: u64,
+    queried: u64,
+    // share of reads served by a query another request had already started
+    hit_rate: f64,
+}
+
+impl CoalescingStats {
+    fn of(reads: &ProductReads) -> Self {
+        let coalesced = reads.coalesced.load(Ordering::Relaxed);
+        let queried = reads.queried.load(Ordering::Relaxed);
+        let total = coalesced + queried;
+        CoalescingStats { coalesced, queried, hit_rate: if total == 0 { 0.0 } else { coalesced as f64 / total as f64 } }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct PoolStats {
+    size: u32,
+    idle: usize,
+}
+
+impl PoolStats {
+    fn of(pool: &SqlitePool) -> Self {
+        PoolStats { size: pool.size(), idle: pool.num_idle() }
+    }
+}
+
+// in-process counters for a quick look during incidents, no metrics backend required
+async fn get_diagnostics(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<DiagnosticsSnapshot>, AppError> {
+    auth.require_admin()?;
+    Ok(Json(DiagnosticsSnapshot {
+        uptime_secs: state.diagnostics.started.elapsed().as_secs(),
+        requests_total: state.diagnostics.requests.load(Ordering::Relaxed),
+        errors_by_variant: state.diagnostics.errors.lock().unwrap().clone(),
+        slow_queries: SLOW_QUERIES.load(Ordering::Relaxed),
+        product_read_coalescing: CoalescingStats::of(&state.product_reads),
+        pool: PoolStats::of(&state.pool),
+        replica_pool: state.replica.as_ref().map(PoolStats::of),
+    }))
+}
+
+async fn get_maintenance(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<MaintenanceState>, AppError> {
+    auth.require_admin()?;
+    Ok(Json(MaintenanceState { enabled: state.maintenance.load(Ordering::SeqCst) }))
+}
+
+async fn set_maintenance(auth: MaybeAuth, State(state): State<Arc<AppState>>, Json(payload): Json<MaintenanceState>) -> Result<Json<MaintenanceState>, AppError> {
+    auth.require_admin()?;
+    state.maintenance.store(payload.enabled, Ordering::SeqCst);
+    info!("maintenance mode {}", if payload.enabled { "enabled" } else { "disabled" });
+    Ok(Json(payload))
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryValueQuery {
+    group_by: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct InventoryValue {
+    total_value_cents: i64,
+    // only present with ?group_by=category; products without a category share the null group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    by_category: Option<Vec<CategoryInventoryValue>>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct CategoryInventoryValue {
+    category_id: Option<i64>,
+    product_count: i64,
+    value_cents: i64,
+}
+
+// on-hand stock valued at the current list price, for active (not soft-deleted) products only.
+// Products without stock tracking have no meaningful on-hand quantity and are left out
+async fn inventory_value(auth: MaybeAuth, Query(params): Query<InventoryValueQuery>, State(state): State<Arc<AppState>>) -> Result<Json<InventoryValue>, AppError> {
+    auth.require_admin()?;
+    let by_category = match params.group_by.as_deref() {
+        None => false,
+        Some("category") => true,
+        Some(other) => return Err(AppError::BadRequest(format!("unsupported group_by {}", other))),
+    };
+
+    // a single aggregate pass; SUM over no rows is NULL, hence the COALESCE for an empty catalog
+    if !by_category {
+        let total: i64 = sqlx::query("SELECT COALESCE(SUM(stock * price_cents), 0) AS value_cents FROM products WHERE deleted_at IS NULL AND track_stock")
+            .fetch_one(state.read_pool())
+            .await?
+            .get("value_cents");
+        return Ok(Json(InventoryValue { total_value_cents: total, by_category: None }));
+    }
+
+    let rows = sqlx::query("SELECT category_id, COUNT(*) AS product_count, COALESCE(SUM(stock * price_cents), 0) AS value_cents FROM products WHERE deleted_at IS NULL AND track_stock GROUP BY category_id ORDER BY category_id")
+        .fetch_all(state.read_pool())
+        .await?;
+    let groups: Vec<CategoryInventoryValue> = rows
+        .into_iter()
+        .map(|r| CategoryInventoryValue {
+            category_id: r.get("category_id"),
+            product_count: r.get("product_count"),
+            value_cents: r.get("value_cents"),
+        })
+        .collect();
+    let total = groups.iter().map(|g| g.value_cents).sum();
+    Ok(Json(InventoryValue { total_value_cents: total, by_category: Some(groups) }))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct VacuumResult {
+    size_before_bytes: i64,
+    size_after_bytes: i64,
+}
+
+async fn database_size_bytes(conn: &mut SqliteConnection) -> Result<i64, AppError> {
+    let page_count: i64 = sqlx::query("PRAGMA page_count").fetch_one(&mut *conn).await?.get(0);
+    let page_size: i64 = sqlx::query("PRAGMA page_size").fetch_one(&mut *conn).await?.get(0);
+    Ok(page_count * page_size)
+}
+
+// VACUUM rebuilds the whole database file and cannot run inside a transaction, so it gets a
+// connection of its own rather than a pooled transaction. It holds an exclusive lock for its
+// whole run: concurrent writers wait on busy_timeout (and may fail if it is exceeded), so run it
+// off-peak or with maintenance mode on
+async fn vacuum_database(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<VacuumResult>, AppError> {
+    auth.require_admin()?;
+    let mut conn = state.pool.acquire().await?;
+
+    let before = database_size_bytes(&mut conn).await?;
+    let started = Instant::now();
+    conn.execute("VACUUM").await?;
+    conn.execute("PRAGMA optimize").await?;
+    let after = database_size_bytes(&mut conn).await?;
+
+    info!(before, after, duration_ms = started.elapsed().as_millis(), "database vacuumed");
+    Ok(Json(VacuumResult { size_before_bytes: before, size_after_bytes: after }))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "camel_case", serde(rename_all = "camelCase"))]
+struct ReindexResult {
+    indexed_products: i64,
+    duration_ms: u64,
+}
+
+// rebuilds the search index from the products table, repairing any drift from writes that
+// bypassed its triggers. The rebuild is one statement, so readers see either the old index or
+// the new one
+async fn reindex_search(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Json<ReindexResult>, AppError> {
+    auth.require_admin()?;
+    let mut conn = state.pool.acquire().await?;
+
+    let started = Instant::now();
+    conn.execute("INSERT INTO products_fts(products_fts) VALUES('rebuild')").await?;
+    let indexed: i64 = sqlx::query("SELECT COUNT(*) FROM products").fetch_one(&mut *conn).await?.get(0);
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    info!(indexed, duration_ms, "search index rebuilt");
+    Ok(Json(ReindexResult { indexed_products: indexed, duration_ms }))
+}
+
+// VACUUM INTO writes a transactionally consistent copy of the database (it reads inside a single
+// read transaction, so concurrent writes are neither blocked for long nor half-included) to a temp
+// file, which is then streamed to the client and removed
+async fn backup_database(auth: MaybeAuth, State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    use tokio::io::AsyncReadExt;
+
+    auth.require_admin()?;
+    let path = std::env::temp_dir().join(format!("ecom-backup-{}.db", Uuid::new_v4()));
+    let path_str = path.to_str().ok_or(AppError::InternalError)?.to_string();
+
+    sqlx::query("VACUUM INTO ?").bind(&path_str).execute(&state.pool).await?;
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("failed to open backup file: {}", e);
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(AppError::InternalError);
+        }
+    };
+    let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    info!(size, "database backup created");
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(8);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send(Ok(buf[..n].to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+        // the snapshot is only needed for this one download
+        drop(file);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("failed to remove backup file {}: {}", path.display(), e);
+        }
+    });
+
+    let filename = format!("backup-{}.db", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    ).into_response())
+}
+
+// rejects write requests with 503 while maintenance mode is on; reads and the admin endpoints
+// stay available so the toggle can always be switched back off
+async fn maintenance_guard(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let is_read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !is_read && state.maintenance.load(Ordering::SeqCst) && !req.uri().path().starts_with(&format!("{}/admin/", state.api_base_path)) {
+        return AppError::Maintenance.into_response();
+    }
+    next.run(req).await
+}
+
+// caps concurrent requests at MAX_CONCURRENT_REQUESTS; excess requests wait up to
+// LOAD_SHED_TIMEOUT for a slot and are shed with 503 after that
+async fn concurrency_guard(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    match tokio::time::timeout(state.load_shed_timeout, state.request_permits.acquire()).await {
+        Ok(Ok(_permit)) => next.run(req).await,
+        _ => AppError::Overloaded.into_response(),
+    }
+}
+
+// incoming ids are only accepted when they look like a UUID or a short token, so a client cannot
+// inject arbitrary text into the logs through the header
+fn valid_request_id(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok() || (!id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+}
+
+async fn access_log(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| state.trust_request_id && valid_request_id(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    // otel.name is only filled in when traces are exported (the otel feature), see telemetry.rs
+    let span = info_span!("request", request_id = %request_id, otel.name = tracing::field::Empty);
+    #[cfg(feature = "otel")]
+    telemetry::start_request(&span, req.headers(), &method, req.extensions().get::<MatchedPath>().map(MatchedPath::as_str));
+    async move {
+        let started = Instant::now();
+        let mut res = next.run(req).await;
+        let status = res.status();
+        #[cfg(feature = "otel")]
+        telemetry::finish_request(&tracing::Span::current(), status);
+        let latency_ms = started.elapsed().as_millis();
+
+        state.diagnostics.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(ErrorVariant(variant)) = res.extensions().get::<ErrorVariant>().copied() {
+            *state.diagnostics.errors.lock().unwrap().entry(variant).or_insert(0) += 1;
+        }
+
+        if status.is_server_error() {
+            error!(%method, %uri, status = status.as_u16(), latency_ms, "request failed");
+        } else if status.is_client_error() {
+            warn!(%method, %uri, status = status.as_u16(), latency_ms, "request rejected");
+        } else if state.access_log_sample_rate >= 1.0 || rand::random::<f64>() < state.access_log_sample_rate {
+            info!(%method, %uri, status = status.as_u16(), latency_ms, "request");
+        }
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+fn api_routes() -> Router<Arc<AppState>> {
+    let routes = Router::new()
+        .route("/products", get(list_products).post(create_product))
+        .route("/products/price-adjust", post(adjust_prices))
+        .route("/products/round-prices", post(round_prices))
+        .route("/products/merge", post(merge_products))
+        .route("/products/export", get(export_products))
+        .route("/products/stock", put(update_stock_levels))
+        .route("/products/bulk-delete", post(bulk_delete_products))
+        .route("/products/facets", get(product_facets))
+        .route("/products/batch-get", post(batch_get_products))
+        .route("/products/:id", get(get_product).put(update_product).patch(update_product).delete(delete_product))
+        .route("/products/:id/restore", post(restore_product))
+        .route("/products/:id/variants", get(list_variants).post(create_variant))
+        .route("/products/:id/variants/:variant_id", delete(delete_variant))
+        .route("/products/:id/price-tiers", put(set_price_tiers))
+        .route("/products/:id/sales", get(product_sales))
+        .route("/products/:id/ledger", get(get_stock_ledger))
+        .route("/products/:id/adjust-stock", post(adjust_stock))
+        .route("/products/:id/schedule-price", post(schedule_price))
+        .route("/products/:id/related", get(related_products))
+        .route("/products/:id/frequently-bought-together", get(frequently_bought_together))
+        .route("/categories", get(list_categories).post(create_category))
+        .route("/categories/:id", put(rename_category))
+        .route("/categories/:id/summary", get(category_summary))
+        .route("/orders", post(create_order))
+        .route("/orders/bulk", post(create_orders_bulk))
+        .route("/orders/preview-stock", post(preview_order_stock))
+        .route("/orders/status-batch", post(order_status_batch))
+        .route("/orders/:id", get(get_order))
+        .route("/orders/:id/status", put(update_order_status))
+        .route("/orders/:id/refund", post(refund_order))
+        .route("/orders/:id/timeline", get(order_timeline))
+        .route("/orders/:id/attachments", get(list_order_attachments).post(upload_order_attachment))
+        .route("/orders/:id/attachments/:attachment_id", get(download_order_attachment))
+        .route("/orders/:id/notes", get(list_order_notes).post(create_order_note))
+        .route("/customers/:customer_id/addresses", get(list_customer_addresses).post(create_customer_address))
+        .route("/customers/:customer_id/addresses/:id", get(get_customer_address).put(replace_customer_address).delete(delete_customer_address))
+        .route("/version", get(get_version))
+        .route("/stats/inventory-value", get(inventory_value))
+        .route("/stats/bestsellers", get(bestsellers))
+        .route("/admin/maintenance", get(get_maintenance).post(set_maintenance))
+        .route("/admin/diagnostics", get(get_diagnostics))
+        .route("/admin/currency-rates", get(list_currency_rates))
+        .route("/admin/reconcile-stock", post(reconcile_stock))
+        .route("/admin/stock-snapshots", get(list_stock_snapshots).post(create_stock_snapshot))
+        .route("/admin/stock-snapshots/:id/restore", post(restore_stock_snapshot))
+        .route("/admin/vacuum", post(vacuum_database))
+        .route("/admin/reindex-search", post(reindex_search))
+        .route("/admin/backup", get(backup_database))
+        .route("/admin/currency-rates/:code", put(upsert_currency_rate).delete(delete_currency_rate))
+        .route("/admin/webhook-subscriptions", get(list_webhook_subscriptions).post(create_webhook_subscription))
+        .route("/admin/webhook-subscriptions/:id", get(get_webhook_subscription).put(update_webhook_subscription).delete(delete_webhook_subscription));
+
+    #[cfg(feature = "graphql")]
+    let routes = routes
+        .route("/graphql", post(graphql::graphql_handler))
+        .route("/graphql/playground", get(graphql::playground));
+
+    routes
+}
+
+async fn add_column_if_missing(conn: &mut SqliteConnection, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(&mut *conn)
+        .await?;
+    if !columns.iter().any(|c| c.get::<String, _>("name") == column) {
+        conn.execute(format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition).as_str()).await?;
+    }
+    Ok(())
+}
+
+async fn handler_404(uri: Uri) -> AppError {
+    AppError::RouteNotFound(uri.path().to_owned())
+}
+
+// axum answers a wrong method on a known path with an empty 405; give it the same JSON error
+// shape as everything else while keeping the Allow header it sets
+async fn method_not_allowed_json(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let res = next.run(req).await;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED || res.headers().contains_key(header::CONTENT_TYPE) {
+        return res;
+    }
+    let mut json_res = AppError::MethodNotAllowed(method, path).into_response();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        json_res.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    json_res
+}
+
+// the body limit is enforced by whichever extractor reads the body and comes back as axum's
+// plain-text 413; swap that for the usual JSON error that also states the limit. Bodies that
+// only grow past the limit while being decompressed end up here the same way
+async fn payload_too_large_json(State(max_bytes): State<usize>, req: Request, next: Next) -> Response {
+    let res = next.run(req).await;
+    if res.status() != StatusCode::PAYLOAD_TOO_LARGE || res.headers().get(header::CONTENT_TYPE).is_some_and(|v| v.as_bytes().starts_with(b"application/json")) {
+        return res;
+    }
+    AppError::PayloadTooLarge(max_bytes).into_response()
+}
+
+// deleting an order takes its items with it
+fn order_items_ddl(table: &str) -> String {
+    format!(
+        r#"CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            product_id INTEGER NOT NULL,
+            variant_id INTEGER,
+            quantity INTEGER NOT NULL,
+            unit_price_cents INTEGER NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE,
+            FOREIGN KEY(product_id) REFERENCES products(id)
+        );"#,
+        table
+    )
+}
+
+// an order's timeline goes with it
+fn order_events_ddl(table: &str) -> String {
+    format!(
+        r#"CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            event_type TEXT NOT NULL,
+            detail TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+        table
+    )
+}
+
+// and so do its discount/surcharge lines
+fn order_adjustments_ddl(table: &str) -> String {
+    format!(
+        r#"CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            label TEXT NOT NULL,
+            amount_cents INTEGER NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+        table
+    )
+}
+
+// databases created before the cascade was declared still have the plain order_id foreign key on
+// `table`. SQLite can't alter a constraint in place, so the table is rebuilt from `ddl` and the
+// `columns` copied over, with foreign key enforcement paused since it can't be toggled inside the
+// transaction
+async fn migrate_order_cascade(conn: &mut SqliteConnection, table: &str, ddl: fn(&str) -> String, columns: &str) -> Result<(), sqlx::Error> {
+    let foreign_keys = sqlx::query(&format!("PRAGMA foreign_key_list({})", table))
+        .fetch_all(&mut *conn)
+        .await?;
+    let cascades = foreign_keys.iter().any(|fk| fk.get::<String, _>("table") == "orders" && fk.get::<String, _>("on_delete") == "CASCADE");
+    if cascades {
+        return Ok(());
+    }
+
+    let rebuild = format!("{}_rebuild", table);
+    conn.execute("PRAGMA foreign_keys = OFF").await?;
+    let rebuilt = async {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+        tx.execute(format!("DROP TABLE IF EXISTS {}", rebuild).as_str()).await?;
+        tx.execute(ddl(&rebuild).as_str()).await?;
+        tx.execute(format!("INSERT INTO {} ({}) SELECT {} FROM {}", rebuild, columns, columns, table).as_str()).await?;
+        tx.execute(format!("DROP TABLE {}", table).as_str()).await?;
+        tx.execute(format!("ALTER TABLE {} RENAME TO {}", rebuild, table).as_str()).await?;
+        tx.commit().await
+    }.await;
+    conn.execute("PRAGMA foreign_keys = ON").await?;
+    rebuilt?;
+    info!("{} rebuilt with ON DELETE CASCADE on order_id", table);
+    Ok(())
+}
+
+// stock_ledger reasons whose ref_id identifies a single operation (an order, or the client's
+// adjustment id), so a second entry for the same product is always a replay. Merges and snapshot
+// restores reference a product or snapshot that can legitimately be involved again
+const REPLAYABLE_STOCK_REASONS: &str = "'order', 'order_expired', 'adjustment'";
+
+// unique (product, reason, ref_id) journal entries for the replayable reasons, and the same per
+// variant in variant_stock_history. Orders used to journal each line separately, so on first run
+// duplicate entries from an order naming a product twice are folded into one (with the summed
+// delta); the ledger total is unchanged
+async fn create_stock_replay_indexes(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    add_column_if_missing(conn, "variant_stock_history", "ref_id", "TEXT").await?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_variant_stock_history_replay ON variant_stock_history(variant_id, reason, ref_id) WHERE ref_id IS NOT NULL").await?;
+
+    let exists = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'idx_stock_ledger_replay'")
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    if exists {
+        return Ok(());
+    }
+    let same_entry = "d.product_id = stock_ledger.product_id AND d.reason = stock_ledger.reason AND d.ref_id = stock_ledger.ref_id";
+    let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+    tx.execute(format!(
+        "UPDATE stock_ledger SET delta = (SELECT SUM(d.delta) FROM stock_ledger d WHERE {same}) \
+         WHERE ref_id IS NOT NULL AND reason IN ({reasons}) AND id = (SELECT MIN(d.id) FROM stock_ledger d WHERE {same})",
+        same = same_entry, reasons = REPLAYABLE_STOCK_REASONS
+    ).as_str()).await?;
+    let folded = tx.execute(format!(
+        "DELETE FROM stock_ledger WHERE ref_id IS NOT NULL AND reason IN ({reasons}) AND id != (SELECT MIN(d.id) FROM stock_ledger d WHERE {same})",
+        same = same_entry, reasons = REPLAYABLE_STOCK_REASONS
+    ).as_str()).await?.rows_affected();
+    tx.execute(format!(
+        "CREATE UNIQUE INDEX idx_stock_ledger_replay ON stock_ledger(product_id, reason, ref_id) WHERE ref_id IS NOT NULL AND reason IN ({})",
+        REPLAYABLE_STOCK_REASONS
+    ).as_str()).await?;
+    tx.commit().await?;
+    if folded > 0 {
+        info!(folded, "folded duplicate stock ledger entries before adding the replay index");
+    }
+    Ok(())
+}
+
+// full-text index over product name and description. It is an external-content FTS5 table: the
+// text lives only in `products` and the triggers keep the index in step with every insert, update
+// and delete. Writes that bypass them (e.g. a bulk import with triggers dropped) leave it stale
+// until POST /admin/reindex-search rebuilds it
+async fn create_search_index(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let existed = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'products_fts'")
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    conn.execute("CREATE VIRTUAL TABLE IF NOT EXISTS products_fts USING fts5(name, description, content='products', content_rowid='id')").await?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS products_fts_ai AFTER INSERT ON products BEGIN \
+             INSERT INTO products_fts(rowid, name, description) VALUES (new.id, new.name, new.description); \
+         END"
+    ).await?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS products_fts_ad AFTER DELETE ON products BEGIN \
+             INSERT INTO products_fts(products_fts, rowid, name, description) VALUES ('delete', old.id, old.name, old.description); \
+         END"
+    ).await?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS products_fts_au AFTER UPDATE OF name, description ON products BEGIN \
+             INSERT INTO products_fts(products_fts, rowid, name, description) VALUES ('delete', old.id, old.name, old.description); \
+             INSERT INTO products_fts(rowid, name, description) VALUES (new.id, new.name, new.description); \
+         END"
+    ).await?;
+    // an existing catalog gets indexed once, when the table is first created
+    if !existed {
+        conn.execute("INSERT INTO products_fts(products_fts) VALUES('rebuild')").await?;
+    }
+    Ok(())
+}
+
+async fn init_db(pool: &SqlitePool, unique_product_names: bool) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
 
     conn.execute(
         r#"CREATE TABLE IF NOT EXISTS products (
@@ -321,63 +5384,545 @@ async fn init_db(pool: &SqlitePool) -> Result<(), sqlx::Error> {.
             description TEXT,
             price_cents INTEGER NOT NULL,
             stock INTEGER NOT NULL DEFAULT 0,
-            created_at TEXT NOT NULL
+            category_id INTEGER,
+            created_by TEXT,
+            updated_by TEXT,
+            available_from TEXT,
+            available_until TEXT,
+            -- set when the product is soft-deleted (e.g. merged into another); such rows are hidden from reads
+            deleted_at TEXT,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
         );"#,
     ).await?;
 
     conn.execute(
         r#"CREATE TABLE IF NOT EXISTS orders (
             id TEXT PRIMARY KEY,
+            status TEXT NOT NULL DEFAULT 'pending',
             total_cents INTEGER NOT NULL,
             created_at TEXT NOT NULL
         );"#,
     ).await?;
 
+    conn.execute(order_items_ddl("order_items").as_str()).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS product_variants (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            product_id INTEGER NOT NULL,
+            sku TEXT NOT NULL UNIQUE,
+            attributes_json TEXT NOT NULL DEFAULT '{}',
+            price_cents INTEGER NOT NULL,
+            stock INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(product_id) REFERENCES products(id)
+        );"#,
+    ).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS price_tiers (
+            product_id INTEGER NOT NULL,
+            min_quantity INTEGER NOT NULL,
+            price_cents INTEGER NOT NULL,
+            PRIMARY KEY(product_id, min_quantity),
+            FOREIGN KEY(product_id) REFERENCES products(id)
+        );"#,
+    ).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS refunds (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            amount_cents INTEGER NOT NULL,
+            reason TEXT,
+            created_by TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_refunds_order ON refunds(order_id)").await?;
+
+    // documents (purchase orders, notes) uploaded against an order, stored inline
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS order_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            created_by TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_order_attachments_order ON order_attachments(order_id)").await?;
+
+    // service annotations on an order; internal ones are never shown to the customer
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS order_notes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            order_id TEXT NOT NULL,
+            author TEXT,
+            body TEXT NOT NULL,
+            internal BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_order_notes_order ON order_notes(order_id)").await?;
+
+    conn.execute(order_events_ddl("order_events").as_str()).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS outbox (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            event_type TEXT NOT NULL,
+            payload_json TEXT NOT NULL,
+            delivered INTEGER NOT NULL DEFAULT 0,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT NOT NULL,
+            last_error TEXT,
+            created_at TEXT NOT NULL
+        );"#,
+    ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_outbox_pending ON outbox(delivered, next_attempt_at)").await?;
+    // set on rows for a webhook subscription; NULL rows go to WEBHOOK_URL
+    add_column_if_missing(&mut conn, "outbox", "subscription_id", "INTEGER").await?;
+
+    // receivers of product events; `events` is a JSON array of event types (see PRODUCT_EVENTS)
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS webhook_subscriptions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL,
+            events TEXT NOT NULL,
+            secret TEXT,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );"#,
+    ).await?;
+
+    conn.execute(order_adjustments_ddl("order_adjustments").as_str()).await?;
+
+    // a customer's saved addresses; customer_id is the token subject, as on orders
     conn.execute(
-        r#"CREATE TABLE IF NOT EXISTS order_items (
+        r#"CREATE TABLE IF NOT EXISTS customer_addresses (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
+            customer_id TEXT NOT NULL,
+            type TEXT NOT NULL CHECK (type IN ('shipping', 'billing')),
+            line1 TEXT NOT NULL,
+            line2 TEXT,
+            city TEXT NOT NULL,
+            region TEXT,
+            postal_code TEXT,
+            country TEXT NOT NULL,
+            is_default BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );"#,
+    ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_customer_addresses_customer ON customer_addresses(customer_id)").await?;
+    // at most one default address of each type per customer
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_customer_addresses_default ON customer_addresses(customer_id, type) WHERE is_default").await?;
+
+    // the addresses an order was placed with, copied from customer_addresses at the time
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS order_addresses (
             order_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            address_id INTEGER,
+            line1 TEXT NOT NULL,
+            line2 TEXT,
+            city TEXT NOT NULL,
+            region TEXT,
+            postal_code TEXT,
+            country TEXT NOT NULL,
+            PRIMARY KEY (order_id, kind),
+            FOREIGN KEY(order_id) REFERENCES orders(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+
+    // every change to products.stock is journaled here; the column is a cache of SUM(delta)
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS stock_ledger (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
             product_id INTEGER NOT NULL,
-            quantity INTEGER NOT NULL,
-            unit_price_cents INTEGER NOT NULL,
-            FOREIGN KEY(order_id) REFERENCES orders(id),
+            delta INTEGER NOT NULL,
+            reason TEXT NOT NULL,
+            ref_id TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(product_id) REFERENCES products(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS price_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            product_id INTEGER NOT NULL,
+            old_price_cents INTEGER NOT NULL,
+            new_price_cents INTEGER NOT NULL,
+            reason TEXT,
+            changed_at TEXT NOT NULL,
+            FOREIGN KEY(product_id) REFERENCES products(id)
+        );"#,
+    ).await?;
+
+    // price changes queued by POST /products/:id/schedule-price; applied_at is set once
+    // scheduled_price_task has applied (or skipped) the row
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS scheduled_prices (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            product_id INTEGER NOT NULL,
+            price_cents INTEGER NOT NULL,
+            effective_at TEXT NOT NULL,
+            created_by TEXT,
+            created_at TEXT NOT NULL,
+            applied_at TEXT,
             FOREIGN KEY(product_id) REFERENCES products(id)
         );"#,
     ).await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_scheduled_prices_pending ON scheduled_prices(effective_at) WHERE applied_at IS NULL").await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS currency_rates (
+            code TEXT PRIMARY KEY,
+            rate_to_base REAL NOT NULL,
+            minor_units INTEGER NOT NULL DEFAULT 2
+        );"#,
+    ).await?;
+
+    // one row per client_adjustment_id, holding what the first request applied
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS stock_adjustments (
+            client_adjustment_id TEXT PRIMARY KEY,
+            product_id INTEGER NOT NULL,
+            delta INTEGER NOT NULL,
+            resulting_stock INTEGER NOT NULL,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY(product_id) REFERENCES products(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS stock_snapshots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_by TEXT,
+            created_at TEXT NOT NULL
+        );"#,
+    ).await?;
 
+    // no foreign key to products: a snapshot keeps its rows even if a product is later removed
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS stock_snapshot_items (
+            snapshot_id INTEGER NOT NULL,
+            product_id INTEGER NOT NULL,
+            stock INTEGER NOT NULL,
+            PRIMARY KEY (snapshot_id, product_id),
+            FOREIGN KEY(snapshot_id) REFERENCES stock_snapshots(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS variant_stock_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            variant_id INTEGER NOT NULL,
+            old_stock INTEGER NOT NULL,
+            new_stock INTEGER NOT NULL,
+            reason TEXT NOT NULL,
+            ref_id TEXT,
+            changed_at TEXT NOT NULL,
+            FOREIGN KEY(variant_id) REFERENCES product_variants(id) ON DELETE CASCADE
+        );"#,
+    ).await?;
+
+    // names are unique ignoring case, so "Shoes" and "shoes" can't both exist
+    conn.execute(
+        r#"CREATE TABLE IF NOT EXISTS categories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );"#,
+    ).await?;
+
+    // columns added after the initial schema; CREATE TABLE IF NOT EXISTS won't add them to existing databases
+    add_column_if_missing(&mut conn, "products", "category_id", "INTEGER").await?;
+    add_column_if_missing(&mut conn, "products", "created_by", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "updated_by", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "available_from", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "available_until", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "deleted_at", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "updated_at", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "metadata", "TEXT").await?;
+    add_column_if_missing(&mut conn, "products", "track_stock", "BOOLEAN NOT NULL DEFAULT 1").await?;
+    conn.execute("UPDATE products SET updated_at = created_at WHERE updated_at IS NULL").await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_products_updated_at ON products(updated_at)").await?;
+    // case-insensitive name uniqueness among live products. Soft-deleted rows are outside the
+    // partial index, so a deleted product's name can be reused, and un-deleting it later fails
+    // if another live product has taken the name meanwhile. Creating the index fails (and so
+    // does startup) while live duplicates exist; they have to be renamed or merged first
+    if unique_product_names {
+        conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_products_name_unique ON products(name COLLATE NOCASE) WHERE deleted_at IS NULL").await?;
+    } else {
+        conn.execute("DROP INDEX IF EXISTS idx_products_name_unique").await?;
+    }
+    create_search_index(&mut conn).await?;
+    add_column_if_missing(&mut conn, "order_items", "variant_id", "INTEGER").await?;
+    migrate_order_cascade(&mut conn, "order_items", order_items_ddl, "id, order_id, product_id, variant_id, quantity, unit_price_cents").await?;
+    migrate_order_cascade(&mut conn, "order_events", order_events_ddl, "id, order_id, event_type, detail, created_at").await?;
+    migrate_order_cascade(&mut conn, "order_adjustments", order_adjustments_ddl, "id, order_id, label, amount_cents").await?;
+    // products that predate the ledger get one opening entry so the ledger sum matches their stock
+    conn.execute(
+        "INSERT INTO stock_ledger (product_id, delta, reason, created_at) \
+         SELECT id, stock, 'opening_balance', updated_at FROM products p WHERE stock != 0 AND NOT EXISTS (SELECT 1 FROM stock_ledger l WHERE l.product_id = p.id)"
+    ).await?;
+    add_column_if_missing(&mut conn, "orders", "status", "TEXT NOT NULL DEFAULT 'pending'").await?;
+    add_column_if_missing(&mut conn, "orders", "customer_id", "TEXT").await?;
+    add_column_if_missing(&mut conn, "orders", "client_ip", "TEXT").await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_orders_customer_created ON orders(customer_id, created_at)").await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_orders_client_ip_created ON orders(client_ip, created_at)").await?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_orders_status_created ON orders(status, created_at)").await?;
+    add_column_if_missing(&mut conn, "orders", "subtotal_cents", "INTEGER").await?;
+    add_column_if_missing(&mut conn, "orders", "tax_cents", "INTEGER NOT NULL DEFAULT 0").await?;
+    // orders placed before tax support were untaxed, so their subtotal is their total
+    conn.execute("UPDATE orders SET subtotal_cents = total_cents WHERE subtotal_cents IS NULL").await?;
+    add_column_if_missing(&mut conn, "orders", "order_number", "INTEGER").await?;
+    // number orders that predate order numbers in creation order, after any already numbered ones
+    conn.execute(
+        "WITH numbered AS (SELECT id, ROW_NUMBER() OVER (ORDER BY created_at, id) + (SELECT COALESCE(MAX(order_number), 0) FROM orders) AS n FROM orders WHERE order_number IS NULL) \
+         UPDATE orders SET order_number = numbered.n FROM numbered WHERE orders.id = numbered.id"
+    ).await?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_orders_order_number ON orders(order_number)").await?;
+    create_stock_replay_indexes(&mut conn).await?;
+
+    // only ever raised: a database already set up by a newer build keeps its newer version
+    if schema_version(&mut conn).await? < SCHEMA_VERSION {
+        conn.execute(format!("PRAGMA user_version = {}", SCHEMA_VERSION).as_str()).await?;
+    }
     Ok(())
 }
 
+// recorded in PRAGMA user_version once init_db has brought a database up to date. Bump it with
+// every change to init_db, so /health/ready can tell a database (typically the read replica,
+// which this process never sets up) that has not received the current schema
+const SCHEMA_VERSION: i64 = 6;
+
+async fn schema_version(conn: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+    Ok(sqlx::query("PRAGMA user_version").fetch_one(&mut *conn).await?.get(0))
+}
+
+// per-connection PRAGMAs, applied by sqlx to every new pool connection. Foreign keys are always
+// on (sqlx's default too, stated here so it can't silently change), the rest is tunable:
+// SQLITE_SYNCHRONOUS (off|normal|full|extra), SQLITE_CACHE_SIZE (pages, or KiB when negative)
+// and SQLITE_TEMP_STORE (default|file|memory)
+fn sqlite_connect_options(url: &str) -> Result<SqliteConnectOptions, Box<dyn std::error::Error>> {
+    let mut options = url.parse::<SqliteConnectOptions>()?.foreign_keys(true);
+    if let Ok(mode) = std::env::var("SQLITE_SYNCHRONOUS") {
+        let mode = mode.parse::<SqliteSynchronous>().map_err(|_| format!("invalid SQLITE_SYNCHRONOUS {:?}", mode))?;
+        options = options.synchronous(mode);
+    }
+    if let Ok(size) = std::env::var("SQLITE_CACHE_SIZE") {
+        let size: i64 = size.parse().map_err(|_| format!("invalid SQLITE_CACHE_SIZE {:?}", size))?;
+        options = options.pragma("cache_size", size.to_string());
+    }
+    if let Ok(store) = std::env::var("SQLITE_TEMP_STORE") {
+        let store = store.to_ascii_lowercase();
+        if !matches!(store.as_str(), "default" | "file" | "memory") {
+            return Err(format!("invalid SQLITE_TEMP_STORE {:?}", store).into());
+        }
+        options = options.pragma("temp_store", store);
+    }
+    Ok(options)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(SlowQueryCounter.with_filter(tracing_subscriber::filter::Targets::new().with_target("sqlx::query", tracing::Level::WARN)));
+    // export doesn't follow RUST_LOG: INFO and up always covers the request and order spans
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(telemetry::layer().with_filter(tracing_subscriber::filter::LevelFilter::INFO));
+    subscriber.init();
 
     let database_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://ecom.db".into());
     info!("Connecting to database at {}", database_url);
 
-    let pool = SqlitePool::connect(&database_url).await?;
-    init_db(&pool).await?;
+    let pool = SqlitePool::connect_with(sqlite_connect_options(&database_url)?).await?;
+    let unique_product_names = std::env::var("UNIQUE_PRODUCT_NAMES").map(|v| v == "true" || v == "1").unwrap_or(false);
+    init_db(&pool, unique_product_names).await?;
 
-    let app_state = Arc::new(AppState { pool });
+    let replica = match std::env::var("READ_DATABASE_URL") {
+        Ok(url) => {
+            info!("Connecting to read replica at {}", url);
+            Some(SqlitePool::connect_with(sqlite_connect_options(&url)?).await?)
+        }
+        Err(_) => None,
+    };
+    let log_pool_choice = std::env::var("LOG_POOL_CHOICE").map(|v| v == "true" || v == "1").unwrap_or(false);
+
+    let jwt_secret = std::env::var("JWT_SECRET").ok();
+    if jwt_secret.is_none() {
+        info!("JWT_SECRET not set; bearer tokens will be rejected");
+    }
+
+    let max_concurrent = std::env::var("MAX_CONCURRENT_REQUESTS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS);
+    // milliseconds
+    let load_shed_timeout = std::env::var("LOAD_SHED_TIMEOUT").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_LOAD_SHED_TIMEOUT_MS);
+
+    let request_timeout = std::env::var("REQUEST_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).filter(|&ms| ms > 0).map(Duration::from_millis);
+
+    let access_log_sample_rate = std::env::var("ACCESS_LOG_SAMPLE_RATE").ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(1.0).clamp(0.0, 1.0);
+
+    let max_name_len = std::env::var("MAX_NAME_LEN").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_NAME_LEN);
+    let max_description_len = std::env::var("MAX_DESCRIPTION_LEN").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_DESCRIPTION_LEN);
+
+    let max_stock = std::env::var("MAX_STOCK").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_STOCK);
+
+    let customer_order_limit = std::env::var("CUSTOMER_ORDER_LIMIT").ok().and_then(|v| v.parse::<i64>().ok()).filter(|&n| n > 0);
+    let attachment_max_bytes = std::env::var("ATTACHMENT_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_ATTACHMENT_MAX_BYTES);
+    let customer_order_window_secs = std::env::var("CUSTOMER_ORDER_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CUSTOMER_ORDER_WINDOW_SECS);
+
+    let list_description_max_chars = std::env::var("LIST_DESCRIPTION_MAX_CHARS").ok().and_then(|v| v.parse().ok());
+    let hide_out_of_stock_default = std::env::var("HIDE_OUT_OF_STOCK_DEFAULT").map(|v| v == "true" || v == "1").unwrap_or(false);
+
+    let product_cache_max_age = std::env::var("PRODUCT_CACHE_MAX_AGE").ok().and_then(|v| v.parse().ok());
+
+    let tax_rate_bps = std::env::var("TAX_RATE_BPS").ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(0).max(0);
+
+    let min_order_total_cents = std::env::var("MIN_ORDER_TOTAL_CENTS").ok().and_then(|v| v.parse::<i64>().ok()).filter(|&c| c > 0).map(Money::from_cents);
+    let min_order_includes_adjustments = std::env::var("MIN_ORDER_INCLUDES_ADJUSTMENTS").map(|v| v == "true" || v == "1").unwrap_or(false);
+
+    let order_expiry = std::env::var("ORDER_EXPIRY_MINUTES").ok().and_then(|v| v.parse::<i64>().ok()).filter(|&m| m > 0).map(chrono::Duration::minutes);
+
+    let api_base_path = std::env::var("API_BASE_PATH").unwrap_or_else(|_| DEFAULT_API_BASE_PATH.into());
+    let api_base_path = format!("/{}", api_base_path.trim_matches('/'));
+    let api_base_path = if api_base_path == "/" { String::new() } else { api_base_path };
 
-    // Simple router configuration without CORS for simplicity
-    // CORS can be added later if needed for frontend integration
-    let app = Router::new()
-        .route("/api/v1/products", get(list_products).post(create_product))
-        .route("/api/v1/products/:id", get(get_product).put(update_product).delete(delete_product))
-        .route("/api/v1/orders", post(create_order))
-        .route("/api/v1/orders/:id", get(get_order))
+    let trust_request_id = std::env::var("TRUST_REQUEST_ID").map(|v| v == "true" || v == "1").unwrap_or(true);
+
+    // the outbox is drained even without WEBHOOK_URL, for webhook subscriptions
+    let webhook_client = webhooks::WebhookClient::from_env().map(Arc::new);
+    if let Some(client) = &webhook_client {
+        tokio::spawn(webhooks::run_outbox(pool.clone(), Arc::clone(client)));
+    }
+
+    let app_state = Arc::new(AppState {
+        pool,
+        jwt_secret,
+        replica,
+        log_pool_choice,
+        request_permits: Semaphore::new(max_concurrent),
+        load_shed_timeout: Duration::from_millis(load_shed_timeout),
+        request_timeout,
+        access_log_sample_rate,
+        max_name_len,
+        max_description_len,
+        max_stock,
+        attachment_max_bytes,
+        list_description_max_chars,
+        hide_out_of_stock_default,
+        customer_order_limit,
+        customer_order_window_secs,
+        product_cache_max_age,
+        tax_rate_bps,
+        min_order_total_cents,
+        min_order_includes_adjustments,
+        order_expiry,
+        #[cfg(feature = "graphql")]
+        graphql_playground: std::env::var("GRAPHQL_PLAYGROUND").map(|v| v == "true" || v == "1").unwrap_or(false),
+        trust_request_id,
+        api_base_path,
+        last_stock_reconciliation: std::sync::Mutex::new(None),
+        webhook_endpoint: webhook_client.as_ref().is_some_and(|client| client.has_endpoint()),
+        maintenance: AtomicBool::new(false),
+        product_reads: ProductReads::default(),
+        price_schedule: tokio::sync::Notify::new(),
+        diagnostics: Diagnostics {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+        },
+    });
+
+    let reconcile_interval = std::env::var("STOCK_RECONCILE_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_STOCK_RECONCILE_INTERVAL_SECS);
+    if reconcile_interval > 0 {
+        let auto_reconcile = std::env::var("AUTO_RECONCILE").map(|v| v == "true" || v == "1").unwrap_or(false);
+        tokio::spawn(stock_reconciliation_task(Arc::clone(&app_state), Duration::from_secs(reconcile_interval), auto_reconcile));
+    }
+
+    if let Some(expiry) = app_state.order_expiry {
+        tokio::spawn(order_expiry_task(Arc::clone(&app_state), expiry));
+    }
+    tokio::spawn(scheduled_price_task(Arc::clone(&app_state)));
+
+    PRETTY_JSON.store(std::env::var("PRETTY_JSON").map(|v| v == "true" || v == "1").unwrap_or(false), Ordering::Relaxed);
+
+    let max_body_bytes = std::env::var("MAX_BODY_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_BODY_BYTES);
+
+    // CORS_ALLOWED_ORIGINS (comma-separated origins, or "*") enables CORS for browser clients served
+    // from another origin; unset, no CORS headers are sent. Browsers cache a preflight answer for
+    // CORS_MAX_AGE_SECS so an SPA doesn't send an OPTIONS request before every call
+    let cors = match std::env::var("CORS_ALLOWED_ORIGINS").ok().filter(|v| !v.trim().is_empty()) {
+        Some(origins) => {
+            let allow_origin = if origins.trim() == "*" {
+                AllowOrigin::any()
+            } else {
+                let mut list = Vec::new();
+                for origin in origins.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+                    list.push(HeaderValue::from_str(origin).map_err(|_| format!("invalid origin {:?} in CORS_ALLOWED_ORIGINS", origin))?);
+                }
+                AllowOrigin::list(list)
+            };
+            let max_age = std::env::var("CORS_MAX_AGE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CORS_MAX_AGE_SECS);
+            let request_id = header::HeaderName::from_static("x-request-id");
+            Some(CorsLayer::new()
+                .allow_origin(allow_origin)
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT_LANGUAGE, header::IF_NONE_MATCH, header::IF_UNMODIFIED_SINCE, request_id.clone()])
+                .expose_headers([header::LOCATION, header::ETAG, header::LAST_MODIFIED, header::RETRY_AFTER, request_id])
+                .max_age(Duration::from_secs(max_age)))
+        }
+        None => None,
+    };
+
+    // API_BASE_PATH (default /api/v1) is where the API is mounted; an empty value or "/" mounts it
+    // at the root. Anything that has to stay at a fixed path regardless of the prefix belongs on
+    // the outer router below, not in api_routes()
+    let root = Router::new().route("/health/ready", get(health_ready));
+    let app = if app_state.api_base_path.is_empty() {
+        root.merge(api_routes())
+    } else {
+        root.nest(&app_state.api_base_path, api_routes())
+    };
+    let app = app
+        .fallback(handler_404)
+        .layer(middleware::from_fn(method_not_allowed_json))
+        .layer(middleware::from_fn_with_state(max_body_bytes, payload_too_large_json))
+        .layer(middleware::from_fn_with_state(Arc::clone(&app_state), maintenance_guard))
+        // the body limit is enforced while extractors read the body, i.e. after decompression,
+        // so a small gzip/zstd payload can't expand past max_body_bytes
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(RequestDecompressionLayer::new())
+        .layer(middleware::from_fn_with_state(Arc::clone(&app_state), concurrency_guard))
+        // preflights are answered here, before the concurrency limit and maintenance mode
+        .layer(option_layer(cors))
+        .layer(middleware::from_fn(negotiate_locale))
+        .layer(middleware::from_fn_with_state(Arc::clone(&app_state), request_deadline))
+        .layer(middleware::from_fn_with_state(Arc::clone(&app_state), access_log))
         .with_state(Arc::clone(&app_state));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     info!("Listening on http://{}", addr);
     
+    // canonical paths have no trailing slash: `/api/v1/products/` is served as `/api/v1/products`.
+    // the layer has to wrap the whole router because Router::layer only runs after routing
+    let app = NormalizePathLayer::trim_trailing_slash().layer(app);
+
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    server::serve(listener, app, server::ConnectionLimits::from_env()).await?;
 
     Ok(())
 }
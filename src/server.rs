@@ -0,0 +1,94 @@
+use axum::extract::ConnectInfo;
+use hyper::{body::Incoming, Request};
+use hyper_util::{rt::{TokioExecutor, TokioIo, TokioTimer}, server::conn::auto, service::TowerToHyperService};
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::net::TcpListener;
+use tower::{Service, ServiceExt};
+use tracing::{debug, warn};
+
+// how long a client may take to send a complete request head (HTTP_HEADER_TIMEOUT_SECS). hyper
+// also runs this clock while a kept-alive HTTP/1 connection waits for its next request, so it
+// doubles as the idle timeout between requests. 0 disables it
+const DEFAULT_HEADER_TIMEOUT_SECS: u64 = 10;
+// how long one connection may be reused for further requests (HTTP_KEEPALIVE_SECS); after that it
+// is closed once the request in flight has been answered. 0 disables keep-alive, so every
+// HTTP/1 connection serves a single request
+const DEFAULT_KEEPALIVE_SECS: u64 = 300;
+// backoff after a failed accept (e.g. out of file descriptors), so the loop doesn't spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// connection-level limits against clients that hold sockets open without finishing requests
+// (slowloris). They act before routing, unlike REQUEST_TIMEOUT_MS, which only bounds a handler's
+// database work once the request has been read
+pub struct ConnectionLimits {
+    header_timeout: Option<Duration>,
+    keep_alive: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    pub fn from_env() -> Self {
+        let env_secs = |name: &str, default: u64| {
+            let secs = std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+            (secs > 0).then(|| Duration::from_secs(secs))
+        };
+        ConnectionLimits {
+            header_timeout: env_secs("HTTP_HEADER_TIMEOUT_SECS", DEFAULT_HEADER_TIMEOUT_SECS),
+            keep_alive: env_secs("HTTP_KEEPALIVE_SECS", DEFAULT_KEEPALIVE_SECS),
+        }
+    }
+}
+
+// accepts connections and serves them with `app`, like axum::serve with
+// into_make_service_with_connect_info, but with the limits above applied to every connection.
+// The header timeout is HTTP/1 only; HTTP/2 (prior knowledge, there is no TLS here) still gets
+// the keep-alive cap
+pub async fn serve<S>(listener: TcpListener, app: S, limits: ConnectionLimits) -> std::io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = axum::response::Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_timeout)
+        .keep_alive(limits.keep_alive.is_some());
+    builder.http2().timer(TokioTimer::new());
+    let builder = Arc::new(builder);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let builder = Arc::clone(&builder);
+        // handlers read the peer address through the ConnectInfo extractor
+        let service = TowerToHyperService::new(app.clone().map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(peer));
+            req
+        }));
+        let keep_alive = limits.keep_alive;
+
+        tokio::spawn(async move {
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            let mut conn = std::pin::pin!(conn);
+            let result = match keep_alive {
+                Some(max_age) => tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = tokio::time::sleep(max_age) => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                },
+                None => conn.await,
+            };
+            // timeouts and clients hanging up mid-request end up here; neither is a server error
+            if let Err(e) = result {
+                debug!(%peer, "connection closed: {}", e);
+            }
+        });
+    }
+}
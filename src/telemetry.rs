@@ -0,0 +1,84 @@
+use axum::http::{HeaderMap, Method, StatusCode};
+use opentelemetry::{global, propagation::Extractor, trace::{Status, TracerProvider as _}, KeyValue};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::{SdkTracerProvider, Tracer}, Resource};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{warn, Span};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+// set once the layer is installed; without it the per-request hooks below do nothing
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// the tracing layer that exports spans over OTLP/HTTP, or None when OTEL_EXPORTER_OTLP_ENDPOINT
+// is unset. The exporter reads the endpoint (and OTEL_EXPORTER_OTLP_HEADERS etc.) itself and
+// sends to <endpoint>/v1/traces; spans are batched, so the last few seconds are lost on a kill.
+// OTEL_SERVICE_NAME defaults to the crate name
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|e| !e.is_empty())?;
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            // the subscriber isn't installed yet, so this can only go to stderr
+            eprintln!("failed to build the OTLP exporter, tracing export disabled: {}", e);
+            return None;
+        }
+    };
+
+    let mut resource = Resource::builder();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION"))).build())
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_tracer_provider(provider);
+    // W3C traceparent/tracestate, so spans join the trace of the upstream service that called us
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    ENABLED.store(true, Ordering::Relaxed);
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+// called by `access_log` for its request span: continues the caller's trace if the request
+// carries a traceparent header and sets the HTTP attributes known before the handler runs
+pub fn start_request(span: &Span, headers: &HeaderMap, method: &Method, route: Option<&str>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    if let Err(e) = span.set_parent(parent) {
+        warn!("could not attach the remote trace context: {}", e);
+    }
+    span.record("otel.name", format!("{} {}", method, route.unwrap_or("unmatched")));
+    span.set_attribute("http.request.method", method.to_string());
+    if let Some(route) = route {
+        span.set_attribute("http.route", route.to_string());
+    }
+}
+
+pub fn finish_request(span: &Span, status: StatusCode) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    span.set_attribute("http.response.status_code", i64::from(status.as_u16()));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+}
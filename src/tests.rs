@@ -0,0 +1,407 @@
+use super::*;
+use serde_json::json;
+use sqlx::sqlite::SqlitePoolOptions;
+
+// a fresh, migrated in-memory database per test. One connection only: every connection to
+// `sqlite::memory:` opens its own empty database
+async fn test_state() -> Arc<AppState> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(sqlite_connect_options("sqlite::memory:").unwrap())
+        .await
+        .unwrap();
+    init_db(&pool, false).await.unwrap();
+
+    Arc::new(AppState {
+        pool,
+        jwt_secret: None,
+        replica: None,
+        log_pool_choice: false,
+        request_permits: Semaphore::new(DEFAULT_MAX_CONCURRENT_REQUESTS),
+        load_shed_timeout: Duration::from_millis(DEFAULT_LOAD_SHED_TIMEOUT_MS),
+        request_timeout: None,
+        access_log_sample_rate: 1.0,
+        max_name_len: DEFAULT_MAX_NAME_LEN,
+        max_description_len: DEFAULT_MAX_DESCRIPTION_LEN,
+        max_stock: DEFAULT_MAX_STOCK,
+        attachment_max_bytes: DEFAULT_ATTACHMENT_MAX_BYTES,
+        list_description_max_chars: None,
+        hide_out_of_stock_default: false,
+        customer_order_limit: None,
+        customer_order_window_secs: DEFAULT_CUSTOMER_ORDER_WINDOW_SECS,
+        product_cache_max_age: None,
+        tax_rate_bps: 0,
+        min_order_total_cents: None,
+        min_order_includes_adjustments: false,
+        order_expiry: None,
+        #[cfg(feature = "graphql")]
+        graphql_playground: false,
+        trust_request_id: true,
+        api_base_path: DEFAULT_API_BASE_PATH.into(),
+        last_stock_reconciliation: std::sync::Mutex::new(None),
+        webhook_endpoint: false,
+        maintenance: AtomicBool::new(false),
+        product_reads: ProductReads::default(),
+        price_schedule: tokio::sync::Notify::new(),
+        diagnostics: Diagnostics {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+        },
+    })
+}
+
+fn admin() -> MaybeAuth {
+    MaybeAuth(Some(Claims { sub: "admin-1".into(), role: Some("admin".into()) }))
+}
+
+fn customer(sub: &str) -> MaybeAuth {
+    MaybeAuth(Some(Claims { sub: sub.into(), role: None }))
+}
+
+fn order_of(items: &[(i64, i32)]) -> CreateOrder {
+    CreateOrder {
+        items: items.iter().map(|&(product_id, quantity)| OrderItemRequest { product_id, variant_id: None, quantity }).collect(),
+        adjustments: Vec::new(),
+        shipping_address_id: None,
+    }
+}
+
+// products 1..=count named "product <n>", priced at 100 cents with `stock` units each
+async fn insert_products(pool: &SqlitePool, count: i64, stock: i32) {
+    let now = Utc::now().to_rfc3339();
+    sqlx::query(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?1) \
+         INSERT INTO products (name, price_cents, stock, created_at, updated_at) SELECT 'product ' || i, 100, ?2, ?3, ?3 FROM n"
+    )
+        .bind(count)
+        .bind(stock)
+        .bind(&now)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn export_is_admin_only() {
+    let state = test_state().await;
+    let res = export_products(MaybeAuth(None), Query(ExportQuery { format: None }), State(state)).await;
+    assert!(matches!(res, Err(AppError::Unauthorized)));
+}
+
+// the export is streamed: the body arrives as one small chunk per row instead of one buffer
+// holding the whole catalog, so memory stays flat however many products there are
+#[tokio::test]
+async fn export_streams_a_large_catalog_row_by_row() {
+    const ROWS: i64 = 100_000;
+    let state = test_state().await;
+    insert_products(&state.pool, ROWS, 3).await;
+
+    let res = export_products(admin(), Query(ExportQuery { format: Some("csv".into()) }), State(state)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut body = res.into_body().into_data_stream();
+    let (mut lines, mut chunks, mut largest_chunk) = (0, 0, 0);
+    while let Some(chunk) = body.try_next().await.unwrap() {
+        chunks += 1;
+        largest_chunk = largest_chunk.max(chunk.len());
+        lines += chunk.iter().filter(|&&b| b == b'\n').count() as i64;
+    }
+
+    // header plus one line per product
+    assert_eq!(lines, ROWS + 1);
+    assert_eq!(chunks, ROWS + 1);
+    assert!(largest_chunk < 256, "largest chunk was {} bytes", largest_chunk);
+}
+
+// a product scheduled for later is not suggested to storefront callers until its window is
+// cleared, which PUT does with an explicit null
+#[tokio::test]
+async fn related_products_respect_the_availability_window() {
+    let state = test_state().await;
+    insert_products(&state.pool, 2, 3).await;
+    let now = Utc::now().to_rfc3339();
+    sqlx::query("INSERT INTO categories (name, created_at, updated_at) VALUES ('tools', ?1, ?1)").bind(&now).execute(&state.pool).await.unwrap();
+    sqlx::query("UPDATE products SET category_id = 1").execute(&state.pool).await.unwrap();
+    let tomorrow = (Utc::now() + chrono::Duration::days(1)).to_rfc3339();
+    sqlx::query("UPDATE products SET available_from = ? WHERE id = 2").bind(&tomorrow).execute(&state.pool).await.unwrap();
+
+    let related = |auth| related_products(Path(1), auth, Query(RelatedQuery { limit: None }), State(Arc::clone(&state)));
+    assert_eq!(related(MaybeAuth(None)).await.unwrap().0.items.len(), 0);
+    assert_eq!(related(admin()).await.unwrap().0.items.len(), 1);
+    assert!(matches!(
+        related_products(Path(2), MaybeAuth(None), Query(RelatedQuery { limit: None }), State(Arc::clone(&state))).await,
+        Err(AppError::NotFound)
+    ));
+
+    // omitting the field keeps the window, null clears it
+    let key = if cfg!(feature = "camel_case") { "availableFrom" } else { "available_from" };
+    let parsed: UpdateProduct = serde_json::from_value(json!({ key: null })).unwrap();
+    assert_eq!(parsed.available_from, Some(None));
+    assert_eq!(serde_json::from_value::<UpdateProduct>(json!({})).unwrap().available_from, None);
+    let update = |body: UpdateProduct| update_product(Path(2), admin(), HeaderMap::new(), State(Arc::clone(&state)), Json(body));
+    let product = update(UpdateProduct { name: Some("renamed".into()), ..Default::default() }).await.unwrap().0;
+    assert_eq!(product.available_from.as_deref(), Some(tomorrow.as_str()));
+    let product = update(UpdateProduct { available_from: Some(None), ..Default::default() }).await.unwrap().0;
+    assert_eq!(product.available_from, None);
+    assert_eq!(related(MaybeAuth(None)).await.unwrap().0.items.len(), 1);
+}
+
+#[tokio::test]
+async fn order_history_is_only_visible_to_the_customer_and_staff() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 5).await;
+    let order = place_order(&state, &order_of(&[(1, 1)]), Some("cust-1"), None).await.unwrap();
+
+    let timeline = |auth| order_timeline(Path(order.id.clone()), auth, State(Arc::clone(&state)));
+    assert!(matches!(timeline(MaybeAuth(None)).await, Err(AppError::Unauthorized)));
+    assert!(matches!(timeline(customer("cust-2")).await, Err(AppError::Forbidden)));
+    assert_eq!(timeline(customer("cust-1")).await.unwrap().0.items.len(), 1);
+    assert_eq!(timeline(admin()).await.unwrap().0.items.len(), 1);
+
+    let statuses = |auth| order_status_batch(auth, State(Arc::clone(&state)), Json(OrderStatusBatchRequest { ids: vec![order.id.clone()] }));
+    assert!(matches!(statuses(MaybeAuth(None)).await, Err(AppError::Unauthorized)));
+    assert_eq!(statuses(customer("cust-2")).await.unwrap().0[0].status, None);
+    assert_eq!(statuses(customer("cust-1")).await.unwrap().0[0].status.as_deref(), Some("pending"));
+
+    let attachments = |auth| list_order_attachments(Path(order.id.clone()), auth, State(Arc::clone(&state)));
+    assert!(matches!(attachments(MaybeAuth(None)).await, Err(AppError::Unauthorized)));
+    assert!(matches!(attachments(customer("cust-2")).await, Err(AppError::Forbidden)));
+    assert!(attachments(customer("cust-1")).await.is_ok());
+    assert!(attachments(admin()).await.is_ok());
+    let download = |auth| download_order_attachment(Path((order.id.clone(), 1)), auth, State(Arc::clone(&state)));
+    assert!(matches!(download(customer("cust-2")).await, Err(AppError::Forbidden)));
+    assert!(matches!(download(customer("cust-1")).await, Err(AppError::NotFound)));
+
+    // by UUID or by order number, other callers can't tell the order exists
+    for key in [order.id.clone(), order.order_number.clone()] {
+        let detail = |auth| get_order(Path(key.clone()), auth, State(Arc::clone(&state)));
+        assert!(matches!(detail(MaybeAuth(None)).await, Err(AppError::NotFound)));
+        assert!(matches!(detail(customer("cust-2")).await, Err(AppError::NotFound)));
+        assert_eq!(detail(customer("cust-1")).await.unwrap().0.id, order.id);
+        assert_eq!(detail(admin()).await.unwrap().0.id, order.id);
+    }
+
+    // an anonymous order stays readable by its UUID, but not by its number
+    let guest = place_order(&state, &order_of(&[(1, 1)]), None, None).await.unwrap();
+    assert_eq!(get_order(Path(guest.id.clone()), MaybeAuth(None), State(Arc::clone(&state))).await.unwrap().0.id, guest.id);
+    assert!(matches!(get_order(Path(guest.order_number), MaybeAuth(None), State(Arc::clone(&state))).await, Err(AppError::NotFound)));
+}
+
+#[tokio::test]
+async fn bestseller_revenue_is_admin_only() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 5).await;
+    place_order(&state, &order_of(&[(1, 2)]), Some("cust-1"), None).await.unwrap();
+
+    let ranking = |auth| bestsellers(auth, Query(SalesQuery { from: None, to: None, limit: None, offset: None }), State(Arc::clone(&state)));
+    let public = ranking(customer("cust-1")).await.unwrap().0.items;
+    assert_eq!((public[0].units_sold, public[0].revenue_cents), (2, None));
+    let staff = ranking(admin()).await.unwrap().0.items;
+    assert_eq!((staff[0].units_sold, staff[0].revenue_cents), (2, Some(200)));
+}
+
+async fn count(pool: &SqlitePool, table: &str) -> i64 {
+    sqlx::query(&format!("SELECT COUNT(*) AS n FROM {}", table)).fetch_one(pool).await.unwrap().get("n")
+}
+
+async fn stock_of(pool: &SqlitePool, product_id: i64) -> i32 {
+    sqlx::query("SELECT stock FROM products WHERE id = ?").bind(product_id).fetch_one(pool).await.unwrap().get("stock")
+}
+
+// a statement failing after earlier lines already took their stock must roll the whole order back
+#[tokio::test]
+async fn failed_order_leaves_stock_unchanged() {
+    let state = test_state().await;
+    insert_products(&state.pool, 2, 5).await;
+    sqlx::query("CREATE TRIGGER fail_second_line BEFORE INSERT ON order_items WHEN NEW.product_id = 2 BEGIN SELECT RAISE(ABORT, 'injected failure'); END")
+        .execute(&state.pool)
+        .await
+        .unwrap();
+
+    let res = place_order(&state, &order_of(&[(1, 2), (2, 3)]), Some("cust-1"), None).await;
+    assert!(matches!(res, Err(AppError::DbError(_))));
+
+    assert_eq!(stock_of(&state.pool, 1).await, 5);
+    assert_eq!(stock_of(&state.pool, 2).await, 5);
+    assert_eq!(count(&state.pool, "orders").await, 0);
+    assert_eq!(count(&state.pool, "order_items").await, 0);
+    assert_eq!(count(&state.pool, "stock_ledger").await, 0);
+}
+
+async fn ledger_entries(pool: &SqlitePool, product_id: i64, reason: &str) -> Vec<i64> {
+    sqlx::query("SELECT delta FROM stock_ledger WHERE product_id = ? AND reason = ? ORDER BY id")
+        .bind(product_id)
+        .bind(reason)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.get("delta"))
+        .collect()
+}
+
+// a soft-deleted product keeps its row but can't be ordered, and the failed order takes no stock
+#[tokio::test]
+async fn soft_deleted_product_cannot_be_ordered() {
+    let state = test_state().await;
+    insert_products(&state.pool, 2, 5).await;
+    let delete = |auth, dry_run| delete_product(Path(2), Query(DeleteQuery { dry_run }), auth, State(Arc::clone(&state)));
+    for dry_run in [true, false] {
+        assert!(matches!(delete(MaybeAuth(None), dry_run).await, Err(AppError::Unauthorized)));
+        assert!(matches!(delete(customer("cust-1"), dry_run).await, Err(AppError::Forbidden)));
+    }
+    delete(admin(), false).await.unwrap();
+
+    let res = place_order(&state, &order_of(&[(1, 1), (2, 1)]), Some("cust-1"), None).await;
+    assert!(matches!(res, Err(AppError::BadRequest(msg)) if msg == "product 2 is not available"));
+    assert_eq!(stock_of(&state.pool, 1).await, 5);
+    assert_eq!(stock_of(&state.pool, 2).await, 5);
+    assert_eq!(count(&state.pool, "orders").await, 0);
+}
+
+// an order naming the same product on two lines, plus a variant, journals one entry per product
+// and per variant, and expiring it restocks each with one entry again
+#[tokio::test]
+async fn orders_journal_one_stock_entry_per_product_and_variant() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 10).await;
+    sqlx::query("INSERT INTO product_variants (product_id, sku, attributes_json, price_cents, stock) VALUES (1, 'V-1', '{}', 150, 4)").execute(&state.pool).await.unwrap();
+    let mut payload = order_of(&[(1, 2), (1, 3), (1, 1)]);
+    payload.items[2].variant_id = Some(1);
+    let order = place_order(&state, &payload, Some("cust-1"), None).await.unwrap();
+
+    assert_eq!(stock_of(&state.pool, 1).await, 5);
+    assert_eq!(ledger_entries(&state.pool, 1, "order").await, vec![-5]);
+    let history = sqlx::query("SELECT old_stock, new_stock, reason, ref_id FROM variant_stock_history WHERE variant_id = 1").fetch_all(&state.pool).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].get::<i64, _>("old_stock"), history[0].get::<i64, _>("new_stock")), (4, 3));
+    assert_eq!(history[0].get::<Option<String>, _>("ref_id").as_deref(), Some(order.id.as_str()));
+
+    assert_eq!(expire_pending_orders(&state, chrono::Duration::zero()).await.unwrap(), 1);
+    assert_eq!(stock_of(&state.pool, 1).await, 10);
+    assert_eq!(ledger_entries(&state.pool, 1, "order_expired").await, vec![5]);
+    assert_eq!(count(&state.pool, "variant_stock_history").await, 2);
+}
+
+// lines naming the same product or variant are checked against their combined quantity
+#[tokio::test]
+async fn duplicate_lines_cannot_overdraw_stock() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 3).await;
+    sqlx::query("INSERT INTO product_variants (product_id, sku, attributes_json, price_cents, stock) VALUES (1, 'V-1', '{}', 150, 3)").execute(&state.pool).await.unwrap();
+
+    let res = place_order(&state, &order_of(&[(1, 2), (1, 2)]), Some("cust-1"), None).await;
+    assert!(matches!(res, Err(AppError::BadRequest(msg)) if msg == "not enough stock for product 1"));
+    let mut payload = order_of(&[(1, 2), (1, 2)]);
+    payload.items.iter_mut().for_each(|item| item.variant_id = Some(1));
+    let res = place_order(&state, &payload, Some("cust-1"), None).await;
+    assert!(matches!(res, Err(AppError::BadRequest(msg)) if msg == "not enough stock for variant 1 of product 1"));
+
+    assert_eq!(stock_of(&state.pool, 1).await, 3);
+    assert_eq!(count(&state.pool, "orders").await, 0);
+    // a product line and a variant line draw on separate stock
+    let mut payload = order_of(&[(1, 3), (1, 3)]);
+    payload.items[1].variant_id = Some(1);
+    place_order(&state, &payload, Some("cust-1"), None).await.unwrap();
+}
+
+#[tokio::test]
+async fn replayed_stock_entry_is_rejected() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 10).await;
+    let mut tx = state.pool.begin().await.unwrap();
+    record_stock_change(&mut tx, 1, -2, "order", Some("order-1")).await.unwrap();
+    assert!(record_stock_change(&mut tx, 1, -2, "order", Some("order-1")).await.is_err());
+    // merges and snapshot restores may reference the same product or snapshot again
+    record_stock_change(&mut tx, 1, 3, "snapshot_restore", Some("7")).await.unwrap();
+    record_stock_change(&mut tx, 1, 3, "snapshot_restore", Some("7")).await.unwrap();
+}
+
+// databases from before the replay index may hold one entry per order line; they are folded on
+// upgrade without changing the ledger total
+#[tokio::test]
+async fn replay_index_migration_folds_duplicate_entries() {
+    let state = test_state().await;
+    insert_products(&state.pool, 1, 10).await;
+    sqlx::query("DROP INDEX idx_stock_ledger_replay").execute(&state.pool).await.unwrap();
+    for delta in [-2, -3] {
+        sqlx::query("INSERT INTO stock_ledger (product_id, delta, reason, ref_id, created_at) VALUES (1, ?, 'order', 'order-1', '2024-01-01T00:00:00+00:00')")
+            .bind(delta)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+    }
+
+    let total = || sqlx::query("SELECT SUM(delta) AS total FROM stock_ledger WHERE product_id = 1").fetch_one(&state.pool);
+    let before: i64 = total().await.unwrap().get("total");
+
+    init_db(&state.pool, false).await.unwrap();
+    assert_eq!(ledger_entries(&state.pool, 1, "order").await, vec![-5]);
+    assert_eq!(total().await.unwrap().get::<i64, _>("total"), before);
+}
+
+#[test]
+fn path_errors_name_the_singular_resource() {
+    assert_eq!(path_error_message(Some("/customers/:customer_id/addresses/:id"), 1), "invalid address id");
+    assert_eq!(path_error_message(Some("/categories/:id"), 0), "invalid category id");
+    assert_eq!(path_error_message(Some("/orders/:id/attachments/:attachment_id"), 0), "invalid order id");
+    assert_eq!(path_error_message(Some("/orders/:id/attachments/:attachment_id"), 1), "invalid attachment id");
+}
+
+// places an order with an adjustment line, for the cascade tests
+async fn place_adjusted_order(state: &AppState) -> String {
+    let order = place_order(state, &order_of(&[(1, 1), (2, 2)]), Some("cust-1"), None).await.unwrap();
+    sqlx::query("INSERT INTO order_adjustments (order_id, label, amount_cents) VALUES (?, 'coupon', -50)")
+        .bind(&order.id)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    order.id
+}
+
+async fn order_rows(pool: &SqlitePool) -> [i64; 3] {
+    [count(pool, "order_items").await, count(pool, "order_events").await, count(pool, "order_adjustments").await]
+}
+
+#[tokio::test]
+async fn deleting_an_order_removes_its_items_events_and_adjustments() {
+    let state = test_state().await;
+    insert_products(&state.pool, 2, 5).await;
+    let order_id = place_adjusted_order(&state).await;
+    assert!(order_rows(&state.pool).await.iter().all(|&n| n > 0));
+
+    sqlx::query("DELETE FROM orders WHERE id = ?").bind(&order_id).execute(&state.pool).await.unwrap();
+    assert_eq!(order_rows(&state.pool).await, [0, 0, 0]);
+}
+
+// tables created before the cascade was declared are rebuilt on upgrade, keeping their rows
+#[tokio::test]
+async fn order_cascade_migration_rebuilds_legacy_tables() {
+    let state = test_state().await;
+    insert_products(&state.pool, 2, 5).await;
+    for (table, ddl) in [("order_events", order_events_ddl("order_events")), ("order_adjustments", order_adjustments_ddl("order_adjustments"))] {
+        sqlx::query(&format!("DROP TABLE {}", table)).execute(&state.pool).await.unwrap();
+        sqlx::query(&ddl.replace(" ON DELETE CASCADE", "")).execute(&state.pool).await.unwrap();
+    }
+    let order_id = place_adjusted_order(&state).await;
+    let before = order_rows(&state.pool).await;
+
+    init_db(&state.pool, false).await.unwrap();
+    assert_eq!(order_rows(&state.pool).await, before);
+    sqlx::query("DELETE FROM orders WHERE id = ?").bind(&order_id).execute(&state.pool).await.unwrap();
+    assert_eq!(order_rows(&state.pool).await, [0, 0, 0]);
+}
+
+// fallback and guard responses carry the usual code and localized message
+#[tokio::test]
+async fn fallback_errors_are_localized_app_errors() {
+    let res = LOCALE.scope(Locale::Es, async { AppError::Maintenance.into_response() }).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()[header::RETRY_AFTER], MAINTENANCE_RETRY_AFTER_SECS.to_string());
+    let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(body, json!({"code": "maintenance", "error": "servicio en mantenimiento"}));
+
+    let res = handler_404(Uri::from_static("/api/v1/nope")).await.into_response();
+    let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(body, json!({"code": "route_not_found", "error": "route not found", "path": "/api/v1/nope"}));
+}
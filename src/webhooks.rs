@@ -0,0 +1,215 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{Row, SqlitePool, Transaction};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 2_000;
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+// first retry waits this long, doubling for every further attempt
+const RETRY_BASE_DELAY_MS: u64 = 500;
+// how often the outbox worker looks for due rows, and how many it sends per pass
+const OUTBOX_POLL_INTERVAL_MS: u64 = 1_000;
+const OUTBOX_BATCH: i64 = 50;
+// rescheduling after a failed outbox delivery: 5s, 10s, 20s, ... capped at an hour
+const OUTBOX_RETRY_BASE_SECS: i64 = 5;
+const OUTBOX_RETRY_MAX_SECS: i64 = 3_600;
+
+// the shared HTTP client, timeouts and retry policy, plus the WEBHOOK_URL receiver if one is
+// configured. Every outbound webhook goes through `deliver_to`, so all of them are signed and
+// retried the same way, whether they go to WEBHOOK_URL or to a webhook subscription
+pub struct WebhookClient {
+    http: reqwest::Client,
+    endpoint: Option<Endpoint>,
+    max_attempts: u32,
+}
+
+struct Endpoint {
+    url: String,
+    secret: Option<String>,
+}
+
+impl WebhookClient {
+    // configured from WEBHOOK_URL, WEBHOOK_SECRET, WEBHOOK_CONNECT_TIMEOUT_MS, WEBHOOK_TIMEOUT_MS
+    // and WEBHOOK_MAX_ATTEMPTS. Without WEBHOOK_URL the client still delivers to webhook
+    // subscriptions; None only if the HTTP client can't be built
+    pub fn from_env() -> Option<Self> {
+        let endpoint = std::env::var("WEBHOOK_URL").ok().filter(|u| !u.is_empty()).map(|url| {
+            let secret = std::env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty());
+            if secret.is_none() {
+                warn!("WEBHOOK_SECRET is not set; webhooks will be sent without an X-Signature header");
+            }
+            Endpoint { url, secret }
+        });
+        let env_u64 = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+
+        let http = match reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(env_u64("WEBHOOK_CONNECT_TIMEOUT_MS", DEFAULT_CONNECT_TIMEOUT_MS)))
+            .timeout(Duration::from_millis(env_u64("WEBHOOK_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)))
+            .build()
+        {
+            Ok(http) => http,
+            Err(e) => {
+                error!("failed to build webhook client, webhooks disabled: {}", e);
+                return None;
+            }
+        };
+        let max_attempts = std::env::var("WEBHOOK_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+
+        Some(WebhookClient { http, endpoint, max_attempts })
+    }
+
+    // whether WEBHOOK_URL is set, i.e. whether `enqueue_outbox` events go anywhere
+    pub fn has_endpoint(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    // retries connection errors, timeouts, 429 and 5xx with exponential backoff; other 4xx
+    // answers are final because resending the same body cannot help. Outbox deliveries also
+    // carry their row id so receivers can drop duplicates
+    async fn deliver_to(&self, url: &str, secret: Option<&str>, event_type: &str, body: &[u8], outbox_id: Option<i64>) -> Result<(), String> {
+        let mut last_error = String::new();
+        for attempt in 1..=self.max_attempts {
+            let mut request = self.http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Webhook-Event", event_type)
+                .body(body.to_vec());
+            if let Some(signature) = secret.and_then(|secret| signature(secret, body)) {
+                request = request.header("X-Signature", signature);
+            }
+            if let Some(outbox_id) = outbox_id {
+                request = request.header("X-Outbox-Id", outbox_id.to_string());
+            }
+
+            match request.send().await {
+                Ok(res) if res.status().is_success() => {
+                    debug!(event_type, attempt, "webhook delivered");
+                    return Ok(());
+                }
+                Ok(res) if res.status().is_client_error() && res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(format!("receiver rejected webhook with {}", res.status()));
+                }
+                Ok(res) => last_error = format!("receiver answered {}", res.status()),
+                Err(e) => last_error = e.to_string(),
+            }
+
+            if attempt < self.max_attempts {
+                tokio::time::sleep(Duration::from_millis(RETRY_BASE_DELAY_MS << (attempt - 1))).await;
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// hex HMAC-SHA256 of the exact body bytes, sent as `X-Signature: sha256=<hex>`
+fn signature(secret: &str, body: &[u8]) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(body);
+    Some(format!("sha256={}", hex::encode(mac.finalize().into_bytes())))
+}
+
+// transactional outbox: the event row is written in the caller's transaction, so it exists if and
+// only if the change it describes was committed. Delivery happens later in `run_outbox`
+pub async fn enqueue_outbox(tx: &mut Transaction<'_, sqlx::Sqlite>, event_type: &str, payload: &serde_json::Value) -> Result<(), sqlx::Error> {
+    let now = Utc::now().to_rfc3339();
+    sqlx::query("INSERT INTO outbox (event_type, payload_json, delivered, attempts, next_attempt_at, created_at) VALUES (?, ?, 0, 0, ?, ?)")
+        .bind(event_type)
+        .bind(payload.to_string())
+        .bind(&now)
+        .bind(&now)
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+// like `enqueue_outbox`, but writes one row per webhook subscription listening for
+// `event_type`; each is delivered to that subscription's url, signed with its secret
+pub async fn enqueue_subscribers(tx: &mut Transaction<'_, sqlx::Sqlite>, event_type: &str, payload: &serde_json::Value) -> Result<(), sqlx::Error> {
+    let now = Utc::now().to_rfc3339();
+    sqlx::query(
+        "INSERT INTO outbox (event_type, payload_json, delivered, attempts, next_attempt_at, created_at, subscription_id) \
+         SELECT ?1, ?2, 0, 0, ?3, ?3, s.id FROM webhook_subscriptions s WHERE EXISTS (SELECT 1 FROM json_each(s.events) WHERE value = ?1)"
+    )
+        .bind(event_type)
+        .bind(payload.to_string())
+        .bind(&now)
+        .execute(tx.as_mut())
+        .await?;
+    Ok(())
+}
+
+// polls for due, undelivered outbox rows and sends them oldest first. A row is only marked
+// delivered after the receiver accepted it, so a crash between send and update re-sends it:
+// delivery is at-least-once and receivers should dedupe on the X-Outbox-Id header
+pub async fn run_outbox(pool: SqlitePool, client: Arc<WebhookClient>) {
+    let mut ticker = tokio::time::interval(Duration::from_millis(OUTBOX_POLL_INTERVAL_MS));
+    loop {
+        ticker.tick().await;
+        if let Err(e) = deliver_due_outbox(&pool, &client).await {
+            error!("outbox delivery pass failed: {}", e);
+        }
+    }
+}
+
+// rows without a subscription are for WEBHOOK_URL and are left alone while it isn't set, so they
+// can't hold up the batch
+async fn deliver_due_outbox(pool: &SqlitePool, client: &WebhookClient) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT o.id, o.event_type, o.payload_json, o.attempts, o.subscription_id, s.url, s.secret FROM outbox o \
+         LEFT JOIN webhook_subscriptions s ON s.id = o.subscription_id \
+         WHERE o.delivered = 0 AND o.next_attempt_at <= ? AND (o.subscription_id IS NOT NULL OR ?) ORDER BY o.id LIMIT ?"
+    )
+        .bind(Utc::now().to_rfc3339())
+        .bind(client.has_endpoint())
+        .bind(OUTBOX_BATCH)
+        .fetch_all(pool)
+        .await?;
+
+    for r in rows {
+        let id: i64 = r.get("id");
+        let event_type: String = r.get("event_type");
+        let payload: String = r.get("payload_json");
+        let attempts: i64 = r.get::<i64, _>("attempts") + 1;
+
+        let target = match (r.get::<Option<i64>, _>("subscription_id"), r.get::<Option<String>, _>("url"), &client.endpoint) {
+            (Some(_), Some(url), _) => Some((url, r.get::<Option<String>, _>("secret"))),
+            (None, _, Some(endpoint)) => Some((endpoint.url.clone(), endpoint.secret.clone())),
+            _ => None,
+        };
+        // the subscription was deleted after the row was written; nobody is left to send it to
+        let Some((url, secret)) = target else {
+            sqlx::query("UPDATE outbox SET delivered = 1, attempts = ?, last_error = 'subscription deleted' WHERE id = ?")
+                .bind(attempts)
+                .bind(id)
+                .execute(pool)
+                .await?;
+            continue;
+        };
+
+        match client.deliver_to(&url, secret.as_deref(), &event_type, payload.as_bytes(), Some(id)).await {
+            Ok(()) => {
+                sqlx::query("UPDATE outbox SET delivered = 1, attempts = ?, last_error = NULL WHERE id = ?")
+                    .bind(attempts)
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+            }
+            Err(e) => {
+                let delay = OUTBOX_RETRY_BASE_SECS.saturating_mul(1 << (attempts - 1).min(20)).min(OUTBOX_RETRY_MAX_SECS);
+                let next_attempt_at = (Utc::now() + chrono::Duration::seconds(delay)).to_rfc3339();
+                warn!(outbox_id = id, %event_type, attempts, retry_in_secs = delay, "outbox delivery failed: {}", e);
+                sqlx::query("UPDATE outbox SET attempts = ?, next_attempt_at = ?, last_error = ? WHERE id = ?")
+                    .bind(attempts)
+                    .bind(&next_attempt_at)
+                    .bind(&e)
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}